@@ -0,0 +1,64 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// The size of the MAC appended to every packet when playing with a secret.
+pub const MAC_SIZE: usize = 16;
+/// Both sides have to derive the same key without talking to each other
+/// first, so the salt can't be random.
+const KEY_SALT: &[u8] = b"ICMPong secret";
+const KEY_ROUNDS: u32 = 100_000;
+
+/// A key derived from the passphrase both players agreed on (`--secret`),
+/// which authenticates every packet sent with it.
+#[derive(Clone)]
+pub struct SecretKey([u8; 32]);
+
+impl std::fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SecretKey(..)")
+    }
+}
+
+impl SecretKey {
+    /// Stretches `passphrase` with PBKDF2, so that guessing it from captured
+    /// packets is slow.
+    pub fn derive(passphrase: &str) -> Self {
+        let mut key = [0; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), KEY_SALT, KEY_ROUNDS, &mut key);
+        Self(key)
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::new_from_slice(&self.0).expect("HMAC takes keys of any size")
+    }
+
+    /// The (truncated) HMAC-SHA256 of `payload`.
+    pub fn sign(&self, payload: &[u8]) -> [u8; MAC_SIZE] {
+        let mut mac = self.mac();
+        mac.update(payload);
+        mac.finalize().into_bytes()[..MAC_SIZE].try_into().unwrap()
+    }
+
+    /// Whether `tag` is the MAC of `payload`, compared in constant time.
+    pub fn verify(&self, payload: &[u8], tag: &[u8]) -> bool {
+        let mut mac = self.mac();
+        mac.update(payload);
+        tag.len() == MAC_SIZE && mac.verify_truncated_left(tag).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_same_secret_verifies() {
+        let key = SecretKey::derive("hunter2");
+        let tag = key.sign(b"payload");
+        assert!(key.verify(b"payload", &tag));
+        assert!(SecretKey::derive("hunter2").verify(b"payload", &tag));
+        assert!(!SecretKey::derive("hunter3").verify(b"payload", &tag));
+        assert!(!key.verify(b"payloaD", &tag));
+        assert!(!key.verify(b"payload", &tag[..MAC_SIZE - 1]));
+    }
+}
@@ -0,0 +1,237 @@
+use crate::PACKET_MAGIC;
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+pub const PUBLIC_KEY_SIZE: usize = 32;
+pub const TAG_SIZE: usize = 16;
+/// Takes the place of the version in encrypted packets, whose real version is
+/// encrypted along with the rest of the header.
+pub const ENCRYPTED_VERSION: u8 = u8::MAX;
+/// The magic, [`ENCRYPTED_VERSION`], the sender's public key and the counter
+/// the nonce is made from, which are all sent in the clear.
+pub const ENCRYPTED_HEADER_SIZE: usize = PACKET_MAGIC.len() + 1 + PUBLIC_KEY_SIZE + 8;
+
+/// Encrypts packets for the peer, with a key agreed on through X25519.
+///
+/// Pings carry our public key in the clear. Everything else is sent as an
+/// envelope holding the sender's public key, a counter and the packet after
+/// the magic, encrypted with ChaCha20-Poly1305. Each direction has a key of
+/// its own, derived along with the sender's public key, so the counters the
+/// nonces are made from only have to be unique per sender.
+pub struct Encryption {
+    secret: StaticSecret,
+    public_key: [u8; PUBLIC_KEY_SIZE],
+    /// The ciphers for sending to and receiving from the last public key used,
+    /// as deriving them isn't free.
+    ciphers: Option<Ciphers>,
+}
+
+struct Ciphers {
+    peer_public_key: [u8; PUBLIC_KEY_SIZE],
+    sending: ChaCha20Poly1305,
+    receiving: ChaCha20Poly1305,
+}
+
+impl std::fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Encryption")
+            .field("public_key", &self.public_key)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for Encryption {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Encryption {
+    /// Generates a new key pair, which is only ever used for one session.
+    pub fn new() -> Self {
+        let secret = StaticSecret::random_from_rng(rand::thread_rng());
+        Self {
+            public_key: PublicKey::from(&secret).to_bytes(),
+            secret,
+            ciphers: None,
+        }
+    }
+
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_SIZE] {
+        self.public_key
+    }
+
+    /// Whether `payload` is an envelope made by [`Encryption::seal`].
+    pub fn is_sealed(payload: &[u8]) -> bool {
+        payload.len() > PACKET_MAGIC.len()
+            && payload.starts_with(PACKET_MAGIC)
+            && payload[PACKET_MAGIC.len()] == ENCRYPTED_VERSION
+    }
+
+    /// The cipher for packets from `sender_public_key`, which is either ours
+    /// or `peer_public_key`.
+    fn cipher(
+        &mut self,
+        peer_public_key: &[u8; PUBLIC_KEY_SIZE],
+        sender_public_key: &[u8; PUBLIC_KEY_SIZE],
+    ) -> &ChaCha20Poly1305 {
+        if !matches!(self.ciphers, Some(ref ciphers) if ciphers.peer_public_key == *peer_public_key)
+        {
+            let shared_secret = self
+                .secret
+                .diffie_hellman(&PublicKey::from(*peer_public_key));
+            // both sides have to put the public keys in the same order
+            let (first, second) = if self.public_key < *peer_public_key {
+                (self.public_key, *peer_public_key)
+            } else {
+                (*peer_public_key, self.public_key)
+            };
+            let hkdf =
+                Hkdf::<Sha256>::new(Some(&[first, second].concat()), shared_secret.as_bytes());
+            let cipher_from = |sender_public_key: &[u8; PUBLIC_KEY_SIZE]| {
+                let mut key = [0; 32];
+                hkdf.expand(
+                    &[b"ICMPong encryption".as_slice(), sender_public_key].concat(),
+                    &mut key,
+                )
+                .expect("32 bytes is a valid length for HKDF-SHA256");
+                ChaCha20Poly1305::new(&key.into())
+            };
+            self.ciphers = Some(Ciphers {
+                peer_public_key: *peer_public_key,
+                sending: cipher_from(&self.public_key),
+                receiving: cipher_from(peer_public_key),
+            });
+        }
+        let ciphers = self.ciphers.as_ref().unwrap();
+        if *sender_public_key == self.public_key {
+            &ciphers.sending
+        } else {
+            &ciphers.receiving
+        }
+    }
+
+    fn nonce(counter: u64) -> Nonce {
+        let mut nonce = Nonce::default();
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        nonce
+    }
+
+    /// Encrypts an encoded packet for the peer. `counter` must never repeat.
+    pub fn seal(
+        &mut self,
+        peer_public_key: &[u8; PUBLIC_KEY_SIZE],
+        counter: u64,
+        packet: &[u8],
+    ) -> Vec<u8> {
+        let public_key = self.public_key;
+        let header = [
+            PACKET_MAGIC.as_slice(),
+            &[ENCRYPTED_VERSION],
+            &public_key,
+            &counter.to_be_bytes(),
+        ]
+        .concat();
+        let ciphertext = self
+            .cipher(peer_public_key, &public_key)
+            .encrypt(
+                &Self::nonce(counter),
+                chacha20poly1305::aead::Payload {
+                    msg: packet.get(PACKET_MAGIC.len()..).unwrap_or_default(),
+                    aad: &header,
+                },
+            )
+            .expect("packets are far too small to fail encrypting");
+        [header, ciphertext].concat()
+    }
+
+    /// Decrypts an envelope, returning the encoded packet and the public key
+    /// of its sender. Our own envelopes are decrypted with the key shared with
+    /// `peer_public_key`.
+    pub fn open(
+        &mut self,
+        envelope: &[u8],
+        peer_public_key: Option<&[u8; PUBLIC_KEY_SIZE]>,
+    ) -> Option<(Vec<u8>, [u8; PUBLIC_KEY_SIZE])> {
+        if !Self::is_sealed(envelope) || envelope.len() < ENCRYPTED_HEADER_SIZE + TAG_SIZE {
+            return None;
+        }
+        let (header, ciphertext) = envelope.split_at(ENCRYPTED_HEADER_SIZE);
+        let key_offset = PACKET_MAGIC.len() + 1;
+        let sender_public_key: [u8; PUBLIC_KEY_SIZE] = header
+            [key_offset..key_offset + PUBLIC_KEY_SIZE]
+            .try_into()
+            .unwrap();
+        let counter =
+            u64::from_be_bytes(header[key_offset + PUBLIC_KEY_SIZE..].try_into().unwrap());
+        let other_public_key = if sender_public_key == self.public_key {
+            *peer_public_key?
+        } else {
+            sender_public_key
+        };
+        let packet = self
+            .cipher(&other_public_key, &sender_public_key)
+            .decrypt(
+                &Self::nonce(counter),
+                chacha20poly1305::aead::Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .ok()?;
+        Some((
+            [PACKET_MAGIC.as_slice(), &packet].concat(),
+            sender_public_key,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_peer_can_open_envelopes() {
+        let mut ours = Encryption::new();
+        let mut theirs = Encryption::new();
+        let packet = [PACKET_MAGIC.as_slice(), b"hello"].concat();
+
+        let envelope = ours.seal(&theirs.public_key(), 1, &packet);
+        assert!(Encryption::is_sealed(&envelope));
+        assert!(!envelope.windows(5).any(|window| window == b"hello"));
+        assert_eq!(
+            theirs.open(&envelope, None),
+            Some((packet.clone(), ours.public_key()))
+        );
+        assert_eq!(
+            ours.open(&envelope, Some(&theirs.public_key())),
+            Some((packet.clone(), ours.public_key()))
+        );
+        assert_eq!(Encryption::new().open(&envelope, None), None);
+
+        let mut tampered = envelope.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(theirs.open(&tampered, None), None);
+        // the counter is part of the nonce
+        let mut replaced = envelope;
+        replaced[ENCRYPTED_HEADER_SIZE - 1] ^= 1;
+        assert_eq!(theirs.open(&replaced, None), None);
+    }
+
+    #[test]
+    fn directions_use_different_keys() {
+        let mut ours = Encryption::new();
+        let mut theirs = Encryption::new();
+        let packet = [PACKET_MAGIC.as_slice(), b"hello"].concat();
+        let sent = ours.seal(&theirs.public_key(), 1, &packet);
+        let received = theirs.seal(&ours.public_key(), 1, &packet);
+        let ciphertext = |envelope: &[u8]| envelope[ENCRYPTED_HEADER_SIZE..].to_vec();
+
+        // even with the same counter, the keystreams differ
+        assert_ne!(ciphertext(&sent), ciphertext(&received));
+        assert!(theirs.open(&sent, None).is_some());
+        assert!(ours.open(&received, None).is_some());
+    }
+}
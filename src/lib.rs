@@ -1,23 +1,122 @@
+pub mod auth;
+pub mod crypto;
+pub mod ratelimit;
+pub mod session;
+pub mod transport;
+
+use auth::{SecretKey, MAC_SIZE};
+use crypto::{Encryption, PUBLIC_KEY_SIZE};
 use num_derive::FromPrimitive;
-use pnet::{
-    packet::{
-        icmpv6::{echo_request::MutableEchoRequestPacket, Icmpv6Types},
-        ip::IpNextHeaderProtocols::Icmpv6,
-    },
-    transport::{transport_channel, TransportChannelType, TransportReceiver, TransportSender},
-};
-use rand::Rng;
-use std::net::Ipv6Addr;
-
-pub const PROTOCOL_VERSION: u8 = 0;
+use ratelimit::RateLimiter;
+use session::{Session, SessionEvent};
+use sha2::{Digest, Sha256};
+use std::{sync::Arc, time::Instant};
+use transport::{EchoRequest, PeerAddress, Transport};
+
+/// The newest protocol version this build speaks.
+///
+/// Version 1 switched every multi-byte field to network (big-endian) byte
+/// order. Version 2 added the [`Handshake`] to Ping and Ready packets, whose
+/// layout is frozen so that any later version can still negotiate with us.
+/// Version 3 added Heartbeat packets. Version 4 added a sequence number after
+/// the data, which the peer answers with an Ack for reliable packets. Version
+/// 5 added a timestamp after that, and numbers state updates too. Version 6
+/// added the session id after the timestamp. Version 7 moved the packet after
+/// the echo identifier and sequence number, which used to hold the start of
+/// the magic and are now the session id and a counter. Version 8 added the
+/// cookie and token after the session id.
+pub const PROTOCOL_VERSION: u8 = 8;
+/// The oldest protocol version this build speaks.
+pub const MINIMUM_PROTOCOL_VERSION: u8 = 8;
+/// Version 0 used the sender's native byte order and is still decoded so that
+/// old peers can be told apart from corrupted packets.
+pub const LEGACY_PROTOCOL_VERSION: u8 = 0;
+/// Bitmap of the optional protocol features this build supports.
+pub const FEATURES: u32 = 0;
+/// Set in the [`Handshake`] by clients that want the session encrypted (see
+/// [`crypto::Encryption`]). Both sides have to, or neither.
+pub const FEATURE_ENCRYPTION: u32 = 1;
+pub const PACKET_MAGIC: &[u8; 7] = b"ICMPong";
+pub const PACKET_DATA_SIZE: usize = 32;
+pub const PACKET_SIZE: usize = FROZEN_PACKET_SIZE + 18;
+/// The size of the header and data, which is all that legacy packets and Ping
+/// and Ready packets from newer versions are guaranteed to share with us.
+const FROZEN_PACKET_SIZE: usize = PACKET_MAGIC.len() + 6 + PACKET_DATA_SIZE;
+pub const MAXIMUM_NAME_LENGTH: usize = PACKET_DATA_SIZE - HANDSHAKE_SIZE - 1;
+const HANDSHAKE_SIZE: usize = 6;
+/// How many public keys of clients we haven't settled on yet are kept.
+const MAXIMUM_CANDIDATE_KEYS: usize = 8;
 
 #[derive(Debug)]
 pub enum IcmPongError {
     CreateSocketError(std::io::Error),
     SendPacketError(std::io::Error),
+    /// The peer doesn't support any of our protocol versions.
+    IncompatibleVersion(Handshake),
+    /// The peer sent a packet in another version than the one agreed on.
+    VersionMismatch(u8),
+    /// Only one side wants encryption. Holds whether it's the peer.
+    EncryptionMismatch(bool),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    /// The payload doesn't start with [`PACKET_MAGIC`], so it isn't ours.
+    NotIcmPong,
+    VersionMismatch(u8),
+    /// The echo identifier isn't the packet's session id.
+    IdentifierMismatch(u16),
+    /// The packet's MAC is missing or wasn't made with our secret.
+    Unauthenticated,
+    /// The packet has a MAC, but we don't have a secret to check it with.
+    SecretRequired,
+    /// The packet is encrypted, but we aren't using encryption.
+    EncryptionRequired,
+    InvalidSize(usize),
+    UnknownPacketType(u8),
+    NameTooLong(usize),
+    InvalidName(std::str::Utf8Error),
+    /// A field holds a value no client would send, like a version range
+    /// that ends before it starts.
+    InvalidField(&'static str),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotIcmPong => write!(f, "not an ICMPong packet"),
+            Self::VersionMismatch(version) => write!(
+                f,
+                "unsupported protocol version: expected {MINIMUM_PROTOCOL_VERSION}-{PROTOCOL_VERSION}, found {version}"
+            ),
+            Self::IdentifierMismatch(identifier) => {
+                write!(f, "echo identifier ({identifier}) isn't the session id")
+            }
+            Self::Unauthenticated => write!(f, "packet isn't authenticated with our secret"),
+            Self::SecretRequired => write!(f, "packet is authenticated, but we have no secret"),
+            Self::EncryptionRequired => write!(f, "packet is encrypted, but we aren't encrypting"),
+            Self::InvalidSize(size) => {
+                write!(
+                    f,
+                    "invalid packet size: expected {PACKET_SIZE}, found {size}"
+                )
+            }
+            Self::UnknownPacketType(packet_type) => {
+                write!(f, "unknown packet type ({packet_type})")
+            }
+            Self::NameTooLong(length) => write!(
+                f,
+                "name is too long: maximum {MAXIMUM_NAME_LENGTH} bytes, found {length}"
+            ),
+            Self::InvalidName(error) => write!(f, "name is not valid UTF-8: {error}"),
+            Self::InvalidField(field) => write!(f, "invalid {field}"),
+        }
+    }
 }
 
-#[derive(Debug, FromPrimitive, PartialEq)]
+impl std::error::Error for DecodeError {}
+
+#[derive(Debug, Clone, Copy, FromPrimitive, PartialEq)]
 pub enum IcmPongPacketType {
     Ping,
     Ready,
@@ -26,63 +125,1251 @@ pub enum IcmPongPacketType {
     BallUpdate,
     ScoreUpdate,
     Disconnect,
+    Heartbeat,
+    Ack,
 }
 
-pub struct IcmPongPacket<'a> {
-    pub version: u8,
-    pub packet_type: IcmPongPacketType,
-    pub packet_data: &'a [u8; 32],
+/// The protocol versions and features a client supports, advertised in Ping
+/// and Ready packets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Handshake {
+    pub minimum_version: u8,
+    pub maximum_version: u8,
+    pub features: u32,
 }
 
-impl<'a> IcmPongPacket<'a> {
-    pub fn new(packet_type: IcmPongPacketType, packet_data: &'a [u8; 32]) -> Self {
+impl Handshake {
+    pub fn local() -> Self {
         Self {
-            version: PROTOCOL_VERSION,
-            packet_type,
-            packet_data,
+            minimum_version: MINIMUM_PROTOCOL_VERSION,
+            maximum_version: PROTOCOL_VERSION,
+            features: FEATURES,
         }
     }
+
+    /// Returns the highest protocol version and the features both sides
+    /// support, or `None` if their version ranges don't overlap.
+    pub fn negotiate(&self, peer: &Handshake) -> Option<(u8, u32)> {
+        let version = self.maximum_version.min(peer.maximum_version);
+        if version < self.minimum_version || version < peer.minimum_version {
+            return None;
+        }
+        Some((version, self.features & peer.features))
+    }
 }
 
-pub struct IcmPongConnection {
-    pub peer: Ipv6Addr,
-    pub tx: TransportSender,
+/// Asks the client at the peer's address to play. Clients in another game
+/// (see [`game_tag`]) don't answer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ping {
+    pub handshake: Handshake,
+    /// 0 outside of any game, and from clients that didn't know about games.
+    pub game: u32,
+}
+
+impl Ping {
+    pub fn new(handshake: Handshake) -> Self {
+        Self { handshake, game: 0 }
+    }
+}
+
+/// Tells several games between the same hosts apart, from the name both
+/// players gave with `--game`. Never 0, which stands for no game.
+pub fn game_tag(name: &str) -> u32 {
+    let hash = Sha256::digest(name.as_bytes());
+    u32::from_be_bytes(hash[..4].try_into().unwrap()).max(1)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ready {
+    pub handshake: Handshake,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PaddlePosition {
+    pub x_position: u16,
+    pub y_position: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BallUpdate {
+    pub x_position: u16,
+    pub y_position: u16,
+    pub x_movement: f32,
+    pub y_movement: f32,
+    pub xf32: f32,
+    pub yf32: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreUpdate {
+    pub score: [u32; 2],
+}
+
+/// Confirms that the reliable packet with `sequence` has arrived.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ack {
+    pub sequence: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IcmPongPacket {
+    Ping(Ping),
+    Ready(Ready),
+    Start,
+    PaddlePosition(PaddlePosition),
+    BallUpdate(BallUpdate),
+    ScoreUpdate(ScoreUpdate),
+    Disconnect,
+    /// Sent periodically so that each side notices when the other is gone.
+    Heartbeat,
+    Ack(Ack),
+}
+
+/// A packet along with its header fields.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OwnedPacket {
+    pub version: u8,
     pub client_id: u32,
+    /// Numbers reliable packets, so that they can be acknowledged and
+    /// duplicates dropped, and state updates, so that stale ones can be.
+    /// Always 0 for other packets.
+    pub sequence: u32,
+    /// When the packet was first sent, in milliseconds since the sender's
+    /// session started.
+    pub timestamp: u32,
+    /// Identifies the game between two clients, so that several can be played
+    /// between the same hosts. 0 until the clients have agreed on one.
+    pub session_id: u16,
+    /// A random challenge the sender picked for the session, sent in Pings and
+    /// Readies (0 in other packets).
+    pub cookie: u32,
+    /// Proves that the sender has seen our packets. In Readies, it's our
+    /// cookie echoed back. Once the session is established, it's the token
+    /// made from both cookies.
+    pub token: u32,
+    pub packet: IcmPongPacket,
 }
 
-impl IcmPongConnection {
-    pub fn new(peer: Ipv6Addr) -> Result<(Self, TransportReceiver), IcmPongError> {
-        let (tx, rx) = match transport_channel(
-            1500,
-            TransportChannelType::Layer4(pnet::transport::TransportProtocol::Ipv6(Icmpv6)),
-        ) {
-            Ok((tx, rx)) => (tx, rx),
-            Err(error) => return Err(IcmPongError::CreateSocketError(error)),
-        };
-        Ok((
-            Self {
-                peer,
-                tx,
-                client_id: rand::thread_rng().gen(),
-            },
-            rx,
-        ))
+impl OwnedPacket {
+    /// Wraps the packet in an echo request, identified by its session id.
+    /// `sequence` should count the echo requests sent. With a `key`, the
+    /// packet's MAC is appended.
+    pub fn to_echo_request(&self, sequence: u16, key: Option<&SecretKey>) -> EchoRequest {
+        echo_request(self.session_id, sequence, self.encode(), key)
     }
 
-    pub fn send_packet(&mut self, packet: IcmPongPacket) -> Result<(), IcmPongError> {
-        let mut packet_payload = [
-            "....ICMPong".as_bytes(),
-            &packet.version.to_ne_bytes(),
-            &self.client_id.to_ne_bytes(),
-            &(packet.packet_type as u8).to_ne_bytes(),
-            packet.packet_data,
+    /// Serializes the packet into the wire format of its protocol version.
+    pub fn encode(&self) -> Vec<u8> {
+        [
+            PACKET_MAGIC.as_slice(),
+            &self.version.to_be_bytes(),
+            &self.client_id.to_be_bytes(),
+            &(self.packet.packet_type() as u8).to_be_bytes(),
+            &self.packet.encode_data(),
+            &self.sequence.to_be_bytes(),
+            &self.timestamp.to_be_bytes(),
+            &self.session_id.to_be_bytes(),
+            &self.cookie.to_be_bytes(),
+            &self.token.to_be_bytes(),
+        ]
+        .concat()
+    }
+}
+
+impl IcmPongPacket {
+    pub fn packet_type(&self) -> IcmPongPacketType {
+        match self {
+            Self::Ping(_) => IcmPongPacketType::Ping,
+            Self::Ready(_) => IcmPongPacketType::Ready,
+            Self::Start => IcmPongPacketType::Start,
+            Self::PaddlePosition(_) => IcmPongPacketType::PaddlePosition,
+            Self::BallUpdate(_) => IcmPongPacketType::BallUpdate,
+            Self::ScoreUpdate(_) => IcmPongPacketType::ScoreUpdate,
+            Self::Disconnect => IcmPongPacketType::Disconnect,
+            Self::Heartbeat => IcmPongPacketType::Heartbeat,
+            Self::Ack(_) => IcmPongPacketType::Ack,
+        }
+    }
+
+    /// Whether the packet has to be retransmitted until the peer acknowledges
+    /// it. The others are either sent again anyway, or only the latest one
+    /// matters.
+    pub fn is_reliable(&self) -> bool {
+        matches!(
+            self,
+            Self::Ready(_) | Self::Start | Self::ScoreUpdate(_) | Self::Disconnect
+        )
+    }
+
+    /// Whether the packet replaces the state sent by older packets of its
+    /// type, so that only the newest one should be applied.
+    pub fn is_state_update(&self) -> bool {
+        matches!(self, Self::PaddlePosition(_) | Self::BallUpdate(_))
+    }
+
+    /// Serializes the packet (as sent by `client_id`) into the wire format of
+    /// protocol `version`, outside of any session and without a sequence
+    /// number, timestamp, cookie or token.
+    pub fn encode(&self, version: u8, client_id: u32) -> Vec<u8> {
+        OwnedPacket {
+            version,
+            client_id,
+            sequence: 0,
+            timestamp: 0,
+            session_id: 0,
+            cookie: 0,
+            token: 0,
+            packet: self.clone(),
+        }
+        .encode()
+    }
+
+    fn encode_data(&self) -> [u8; PACKET_DATA_SIZE] {
+        let mut data = [0; PACKET_DATA_SIZE];
+        let encode_handshake = |data: &mut [u8; PACKET_DATA_SIZE], handshake: &Handshake| {
+            data[0] = handshake.minimum_version;
+            data[1] = handshake.maximum_version;
+            data[2..6].copy_from_slice(&handshake.features.to_be_bytes());
+        };
+        match self {
+            Self::Start | Self::Disconnect | Self::Heartbeat => (),
+            Self::Ping(ping) => {
+                encode_handshake(&mut data, &ping.handshake);
+                data[HANDSHAKE_SIZE..HANDSHAKE_SIZE + 4].copy_from_slice(&ping.game.to_be_bytes());
+            }
+            Self::Ready(ready) => {
+                encode_handshake(&mut data, &ready.handshake);
+                if let Some(ref name) = ready.name {
+                    // without splitting a character, which would make the
+                    // name invalid UTF-8
+                    let mut length = name.len().min(MAXIMUM_NAME_LENGTH);
+                    while !name.is_char_boundary(length) {
+                        length -= 1;
+                    }
+                    let name = &name.as_bytes()[..length];
+                    data[HANDSHAKE_SIZE] = name.len() as u8;
+                    data[HANDSHAKE_SIZE + 1..HANDSHAKE_SIZE + name.len() + 1].copy_from_slice(name);
+                }
+            }
+            Self::PaddlePosition(paddle) => {
+                data[0..2].copy_from_slice(&paddle.x_position.to_be_bytes());
+                data[2..4].copy_from_slice(&paddle.y_position.to_be_bytes());
+            }
+            Self::BallUpdate(ball) => {
+                data[0..2].copy_from_slice(&ball.x_position.to_be_bytes());
+                data[2..4].copy_from_slice(&ball.y_position.to_be_bytes());
+                data[4..8].copy_from_slice(&ball.x_movement.to_be_bytes());
+                data[8..12].copy_from_slice(&ball.y_movement.to_be_bytes());
+                data[12..16].copy_from_slice(&ball.xf32.to_be_bytes());
+                data[16..20].copy_from_slice(&ball.yf32.to_be_bytes());
+            }
+            Self::ScoreUpdate(score) => {
+                data[0..4].copy_from_slice(&score.score[0].to_be_bytes());
+                data[4..8].copy_from_slice(&score.score[1].to_be_bytes());
+            }
+            Self::Ack(ack) => data[0..4].copy_from_slice(&ack.sequence.to_be_bytes()),
+        }
+        data
+    }
+
+    /// Parses the packet in an echo request, which must be identified by its
+    /// session id. Packets of versions before 7 are found in the identifier
+    /// and sequence number too, so that old peers are still recognized.
+    ///
+    /// With a `key`, only packets carrying a valid MAC are accepted.
+    pub fn decode_echo_request(
+        request: &EchoRequest,
+        key: Option<&SecretKey>,
+    ) -> Result<OwnedPacket, DecodeError> {
+        let payload = Self::authenticate(request, key)?;
+        if Encryption::is_sealed(&payload) {
+            return Err(DecodeError::EncryptionRequired);
+        }
+        let (packet, _) = Self::decode_payload(request.identifier, &payload, key.is_some())?;
+        Ok(packet)
+    }
+
+    /// Checks the MAC of an echo request with `key`, and returns the payload
+    /// without it.
+    fn authenticate(
+        request: &EchoRequest,
+        key: Option<&SecretKey>,
+    ) -> Result<Vec<u8>, DecodeError> {
+        let old_layout = [
+            request.identifier.to_be_bytes().as_slice(),
+            &request.sequence.to_be_bytes(),
+            &request.payload,
         ]
         .concat();
-        let mut icmp_packet = MutableEchoRequestPacket::new(&mut packet_payload).unwrap();
-        icmp_packet.set_icmpv6_type(Icmpv6Types::EchoRequest);
-        match self.tx.send_to(icmp_packet, self.peer.into()) {
-            Ok(_) => Ok(()),
-            Err(error) => Err(IcmPongError::SendPacketError(error)),
+        if old_layout.starts_with(PACKET_MAGIC) {
+            // nothing before version 7 could be authenticated
+            if key.is_some() {
+                return Err(DecodeError::Unauthenticated);
+            }
+            return Ok(old_layout);
+        }
+        match key {
+            Some(key) => {
+                let Some(mac_offset) = request.payload.len().checked_sub(MAC_SIZE) else {
+                    return Err(DecodeError::Unauthenticated);
+                };
+                let (payload, mac) = request.payload.split_at(mac_offset);
+                if !key.verify(payload, mac) {
+                    return Err(DecodeError::Unauthenticated);
+                }
+                Ok(payload.to_vec())
+            }
+            None => Ok(request.payload.clone()),
+        }
+    }
+
+    /// Parses an authenticated (and decrypted) payload, along with the public
+    /// key that follows Pings from clients that want encryption.
+    fn decode_payload(
+        identifier: u16,
+        payload: &[u8],
+        authenticated: bool,
+    ) -> Result<(OwnedPacket, Option<[u8; PUBLIC_KEY_SIZE]>), DecodeError> {
+        let split = payload
+            .get(..PACKET_SIZE)
+            .map(|_| payload.split_at(PACKET_SIZE));
+        let (payload, public_key) = match split {
+            Some((packet, public_key))
+                if packet[7] == PROTOCOL_VERSION
+                    && packet[12] == IcmPongPacketType::Ping as u8
+                    && public_key.len() == PUBLIC_KEY_SIZE =>
+            {
+                (packet, Some(public_key.try_into().unwrap()))
+            }
+            _ => (payload, None),
+        };
+        let packet = match Self::decode(payload) {
+            Err(DecodeError::InvalidSize(size))
+                if !authenticated
+                    && (size == PACKET_SIZE + MAC_SIZE
+                        || size == PACKET_SIZE + PUBLIC_KEY_SIZE + MAC_SIZE) =>
+            {
+                return Err(DecodeError::SecretRequired)
+            }
+            result => result?,
+        };
+        // older versions had the magic there, and newer ones might use the
+        // identifier for something else
+        if (MINIMUM_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&packet.version)
+            && identifier != packet.session_id
+        {
+            return Err(DecodeError::IdentifierMismatch(identifier));
+        }
+        Ok((packet, public_key))
+    }
+
+    /// Parses a packet of a supported or the legacy protocol version. Ping and
+    /// Ready packets from newer versions are parsed too, so that the versions
+    /// can be negotiated. Callers must check [`OwnedPacket::version`] before
+    /// trusting any other fields.
+    pub fn decode(packet: &[u8]) -> Result<OwnedPacket, DecodeError> {
+        if packet.len() < PACKET_MAGIC.len() + 1 || &packet[0..7] != PACKET_MAGIC {
+            return Err(DecodeError::NotIcmPong);
         }
+        let version = packet[7];
+        let native_byte_order = version == LEGACY_PROTOCOL_VERSION;
+        let newer_handshake = version > PROTOCOL_VERSION
+            && packet.len() >= FROZEN_PACKET_SIZE
+            && (packet[12] == IcmPongPacketType::Ping as u8
+                || packet[12] == IcmPongPacketType::Ready as u8);
+        if !native_byte_order
+            && !(MINIMUM_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+            && !newer_handshake
+        {
+            return Err(DecodeError::VersionMismatch(version));
+        }
+        let (packet, expected_size) = if newer_handshake {
+            (&packet[..FROZEN_PACKET_SIZE], FROZEN_PACKET_SIZE)
+        } else if native_byte_order {
+            (packet, FROZEN_PACKET_SIZE)
+        } else {
+            (packet, PACKET_SIZE)
+        };
+        if packet.len() != expected_size {
+            return Err(DecodeError::InvalidSize(packet.len()));
+        }
+        let u32_from = |bytes: &[u8]| {
+            let bytes = bytes.try_into().unwrap();
+            if native_byte_order {
+                u32::from_ne_bytes(bytes)
+            } else {
+                u32::from_be_bytes(bytes)
+            }
+        };
+        let client_id = u32_from(&packet[8..12]);
+        let packet_type = num_traits::FromPrimitive::from_u8(packet[12])
+            .ok_or(DecodeError::UnknownPacketType(packet[12]))?;
+        let data: &[u8; PACKET_DATA_SIZE] = packet[13..FROZEN_PACKET_SIZE].try_into().unwrap();
+        let (sequence, timestamp, session_id, cookie, token) = if packet.len() == PACKET_SIZE {
+            let trailer = &packet[FROZEN_PACKET_SIZE..];
+            (
+                u32_from(&trailer[0..4]),
+                u32_from(&trailer[4..8]),
+                u16::from_be_bytes([trailer[8], trailer[9]]),
+                u32_from(&trailer[10..14]),
+                u32_from(&trailer[14..18]),
+            )
+        } else {
+            (0, 0, 0, 0, 0)
+        };
+        let u16_at = |offset: usize| {
+            let bytes = [data[offset], data[offset + 1]];
+            if native_byte_order {
+                u16::from_ne_bytes(bytes)
+            } else {
+                u16::from_be_bytes(bytes)
+            }
+        };
+        let u32_at = |offset: usize| u32_from(&data[offset..offset + 4]);
+        let f32_at = |offset: usize| f32::from_bits(u32_at(offset));
+
+        // legacy clients don't advertise a version range, so they can only
+        // ever speak their own version
+        let handshake = if native_byte_order {
+            Handshake {
+                minimum_version: version,
+                maximum_version: version,
+                features: 0,
+            }
+        } else {
+            Handshake {
+                minimum_version: data[0],
+                maximum_version: data[1],
+                features: u32_at(2),
+            }
+        };
+
+        if handshake.minimum_version > handshake.maximum_version
+            && matches!(
+                packet_type,
+                IcmPongPacketType::Ping | IcmPongPacketType::Ready
+            )
+        {
+            return Err(DecodeError::InvalidField("version range"));
+        }
+
+        let packet = match packet_type {
+            IcmPongPacketType::Ping => Self::Ping(Ping {
+                handshake,
+                game: if native_byte_order {
+                    0
+                } else {
+                    u32_at(HANDSHAKE_SIZE)
+                },
+            }),
+            IcmPongPacketType::Ready => {
+                let (name_offset, maximum_name_length) = if native_byte_order {
+                    (0, PACKET_DATA_SIZE - 1)
+                } else {
+                    (HANDSHAKE_SIZE, MAXIMUM_NAME_LENGTH)
+                };
+                let name_length = data[name_offset] as usize;
+                if name_length > maximum_name_length {
+                    return Err(DecodeError::NameTooLong(name_length));
+                }
+                let name = if name_length == 0 {
+                    None
+                } else {
+                    Some(
+                        std::str::from_utf8(&data[name_offset + 1..name_offset + name_length + 1])
+                            .map_err(DecodeError::InvalidName)?
+                            .to_string(),
+                    )
+                };
+                Self::Ready(Ready { handshake, name })
+            }
+            IcmPongPacketType::Start => Self::Start,
+            IcmPongPacketType::PaddlePosition => Self::PaddlePosition(PaddlePosition {
+                x_position: u16_at(0),
+                y_position: u16_at(2),
+            }),
+            IcmPongPacketType::BallUpdate => {
+                let ball = BallUpdate {
+                    x_position: u16_at(0),
+                    y_position: u16_at(2),
+                    x_movement: f32_at(4),
+                    y_movement: f32_at(8),
+                    xf32: f32_at(12),
+                    yf32: f32_at(16),
+                };
+                if !ball.x_movement.is_finite() || !ball.y_movement.is_finite() {
+                    return Err(DecodeError::InvalidField("ball movement"));
+                }
+                if !ball.xf32.is_finite() || !ball.yf32.is_finite() {
+                    return Err(DecodeError::InvalidField("ball position"));
+                }
+                Self::BallUpdate(ball)
+            }
+            IcmPongPacketType::ScoreUpdate => Self::ScoreUpdate(ScoreUpdate {
+                score: [u32_at(0), u32_at(4)],
+            }),
+            IcmPongPacketType::Disconnect => Self::Disconnect,
+            IcmPongPacketType::Heartbeat => Self::Heartbeat,
+            IcmPongPacketType::Ack => Self::Ack(Ack {
+                sequence: u32_at(0),
+            }),
+        };
+        Ok(OwnedPacket {
+            version,
+            client_id,
+            sequence,
+            timestamp,
+            session_id,
+            cookie,
+            token,
+            packet,
+        })
+    }
+}
+
+/// Wraps an encoded (and possibly encrypted) packet in an echo request. With
+/// a `key`, the MAC of the payload is appended.
+fn echo_request(
+    identifier: u16,
+    sequence: u16,
+    mut payload: Vec<u8>,
+    key: Option<&SecretKey>,
+) -> EchoRequest {
+    if let Some(key) = key {
+        let mac = key.sign(&payload);
+        payload.extend_from_slice(&mac);
+    }
+    EchoRequest {
+        identifier,
+        sequence,
+        payload,
+    }
+}
+
+pub struct IcmPongConnection {
+    pub peer: PeerAddress,
+    /// Other addresses the peer might be reachable at (when its hostname
+    /// resolved to several), until one of them answers the handshake.
+    pub candidates: Vec<PeerAddress>,
+    pub transport: Arc<dyn Transport>,
+    pub session: Session,
+    /// Authenticates every packet, if the players agreed on a secret.
+    pub key: Option<SecretKey>,
+    /// How many packets from the peer's address were dropped for not being
+    /// authenticated, or being invalid.
+    pub rejected_packets: u64,
+    /// Why the last of them was dropped.
+    pub rejection: Option<String>,
+    /// Drops packets from the peer's address that arrive too fast (which
+    /// count as rejected too).
+    pub rate_limiter: RateLimiter,
+    /// Set through [`IcmPongConnection::enable_encryption`].
+    encryption: Option<Encryption>,
+    /// The public key of the client whose Ready established the session,
+    /// which can't change anymore.
+    peer_public_key: Option<[u8; PUBLIC_KEY_SIZE]>,
+    /// The public keys that came with handshake packets until then, along
+    /// with the client id and cookie of their sender. The newest are last.
+    candidate_keys: Vec<(u32, u32, [u8; PUBLIC_KEY_SIZE])>,
+    /// How many echo requests were sent. Its lower half is their sequence
+    /// number, and all of it makes up the nonce of encrypted packets.
+    packets_sent: u64,
+}
+
+impl IcmPongConnection {
+    pub fn new(peer: PeerAddress, transport: Arc<dyn Transport>, name: Option<String>) -> Self {
+        Self {
+            peer,
+            candidates: Vec::new(),
+            transport,
+            session: Session::new(name),
+            key: None,
+            rejected_packets: 0,
+            rejection: None,
+            rate_limiter: RateLimiter::new(),
+            encryption: None,
+            peer_public_key: None,
+            candidate_keys: Vec::new(),
+            packets_sent: 0,
+        }
+    }
+
+    /// Encrypts everything but Pings, with a key agreed on during the
+    /// handshake. The peer has to do the same.
+    pub fn enable_encryption(&mut self) {
+        self.session.encrypt = true;
+        self.encryption = Some(Encryption::new());
+    }
+
+    /// Parses a packet from the peer's address, checking its MAC and
+    /// decrypting it if needed.
+    pub fn decode(&mut self, request: &EchoRequest) -> Result<OwnedPacket, DecodeError> {
+        let mut payload = IcmPongPacket::authenticate(request, self.key.as_ref())?;
+        let sealed = Encryption::is_sealed(&payload);
+        let mut sender_public_key = None;
+        if sealed {
+            let Some(ref mut encryption) = self.encryption else {
+                return Err(DecodeError::EncryptionRequired);
+            };
+            let (packet, public_key) = encryption
+                .open(&payload, self.peer_public_key.as_ref())
+                .ok_or(DecodeError::Unauthenticated)?;
+            if public_key != encryption.public_key() {
+                if self.session.is_established() && self.peer_public_key != Some(public_key) {
+                    return Err(DecodeError::Unauthenticated);
+                }
+                sender_public_key = Some(public_key);
+            }
+            payload = packet;
+        }
+        let (packet, public_key) =
+            IcmPongPacket::decode_payload(request.identifier, &payload, self.key.is_some())?;
+        if let (Some(encryption), false) = (&self.encryption, sealed) {
+            match packet.packet {
+                IcmPongPacket::Ping(_) => {
+                    sender_public_key = public_key.filter(|key| *key != encryption.public_key())
+                }
+                // so that the session can tell a peer that doesn't encrypt
+                IcmPongPacket::Ready(_) => (),
+                _ => return Err(DecodeError::Unauthenticated),
+            }
+        }
+        // anyone can send us their key, so it's only pinned once their Ready
+        // has echoed our cookie
+        if let (Some(public_key), false) = (sender_public_key, self.session.is_established()) {
+            self.candidate_keys.retain(|(client_id, cookie, _)| {
+                (*client_id, *cookie) != (packet.client_id, packet.cookie)
+            });
+            if self.candidate_keys.len() == MAXIMUM_CANDIDATE_KEYS {
+                self.candidate_keys.remove(0);
+            }
+            self.candidate_keys
+                .push((packet.client_id, packet.cookie, public_key));
+        }
+        Ok(packet)
+    }
+
+    /// Counts a packet from the peer's address that was dropped, and why.
+    pub fn reject(&mut self, reason: impl std::fmt::Display) {
+        self.rejected_packets += 1;
+        self.rejection = Some(reason.to_string());
+    }
+
+    /// Whether `source` is the peer, or one of the addresses it might be at.
+    pub fn is_peer(&self, source: &PeerAddress) -> bool {
+        self.peer.matches(source)
+            || self
+                .candidates
+                .iter()
+                .any(|candidate| candidate.matches(source))
+    }
+
+    /// Settles on the candidate address `source` as the peer, once it has
+    /// answered the handshake.
+    pub fn settle_peer(&mut self, source: &PeerAddress) {
+        if let Some(candidate) = self
+            .candidates
+            .iter()
+            .find(|candidate| candidate.matches(source))
+        {
+            self.peer = *candidate;
+        }
+        self.candidates.clear();
+    }
+
+    /// Passes `event` on to the session and sends whatever it answers with.
+    /// Returns the game update the peer sent, if any.
+    pub fn handle(&mut self, event: SessionEvent) -> Result<Option<IcmPongPacket>, IcmPongError> {
+        let was_established = self.session.is_established();
+        let transition = self.session.handle(event)?;
+        if !was_established && self.session.is_established() {
+            let peer = (
+                self.session.peer().unwrap().client_id,
+                self.session.peer_cookie(),
+            );
+            self.peer_public_key = self
+                .candidate_keys
+                .iter()
+                .find(|(client_id, cookie, _)| (*client_id, *cookie) == peer)
+                .map(|(_, _, public_key)| *public_key);
+            self.candidate_keys.clear();
+        }
+        for packet in &transition.packets {
+            self.transmit(packet)?;
+        }
+        Ok(transition.update)
+    }
+
+    /// Sends a packet to the peer, through the session so that reliable ones
+    /// are retransmitted until the peer acknowledges them.
+    pub fn send_packet(&mut self, packet: IcmPongPacket) -> Result<(), IcmPongError> {
+        let packet = self.session.outgoing(packet, Instant::now());
+        self.transmit(&packet)
+    }
+
+    /// Sends a packet as is (apart from its MAC and encryption) to the peer,
+    /// or to every address it might be at until one of them has answered. Only
+    /// fails if none of them could be sent.
+    pub fn transmit(&mut self, packet: &OwnedPacket) -> Result<(), IcmPongError> {
+        let destinations = if self.candidates.is_empty() {
+            std::slice::from_ref(&self.peer)
+        } else {
+            self.candidates.as_slice()
+        };
+        self.packets_sent += 1;
+        let mut payload = packet.encode();
+        if let Some(ref mut encryption) = self.encryption {
+            // Readies answering a Ping go to whoever sent it
+            let candidate_key = || match packet.packet {
+                IcmPongPacket::Ready(_) => self
+                    .candidate_keys
+                    .iter()
+                    .rev()
+                    .find(|(_, cookie, _)| *cookie == packet.token)
+                    .map(|(_, _, public_key)| *public_key),
+                _ => None,
+            };
+            if let IcmPongPacket::Ping(_) = packet.packet {
+                payload.extend_from_slice(&encryption.public_key());
+            } else if let Some(ref peer_public_key) = self.peer_public_key.or_else(candidate_key) {
+                payload = encryption.seal(peer_public_key, self.packets_sent, &payload);
+            } else {
+                // only Pings are sent before we know who the peer is
+                return Ok(());
+            }
+        }
+        let request = echo_request(
+            packet.session_id,
+            self.packets_sent as u16,
+            payload,
+            self.key.as_ref(),
+        );
+        let mut sent = false;
+        let mut error = None;
+        for destination in destinations {
+            match self.transport.send_to(&request, destination) {
+                Ok(_) => sent = true,
+                Err(send_error) => error = Some(IcmPongError::SendPacketError(send_error)),
+            }
+        }
+        match error {
+            Some(error) if !sent => Err(error),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transport::LoopbackTransport;
+
+    #[test]
+    fn loopback_connections_exchange_packets() {
+        let first_address: PeerAddress = "fe80::1%1".parse().unwrap();
+        let second_address: PeerAddress = "192.0.2.2".parse().unwrap();
+        let (first_transport, second_transport) =
+            LoopbackTransport::pair(first_address, second_address);
+        let mut first = IcmPongConnection::new(second_address, Arc::new(first_transport), None);
+        let second = IcmPongConnection::new(first_address, Arc::new(second_transport), None);
+
+        first
+            .send_packet(IcmPongPacket::Ping(Ping::new(Handshake::local())))
+            .unwrap();
+        let (request, source) = second.transport.receive_from().unwrap();
+        assert_eq!(source, first_address);
+        assert_eq!(request.sequence, 1);
+        let packet = IcmPongPacket::decode_echo_request(&request, None).unwrap();
+        assert_eq!(packet.client_id, first.session.client_id());
+        assert_eq!(
+            packet.packet,
+            IcmPongPacket::Ping(Ping::new(Handshake::local()))
+        );
+        assert_eq!(
+            second
+                .transport
+                .receive_timeout(std::time::Duration::from_millis(10))
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn encrypted_connections_agree_on_a_key() {
+        let first_address: PeerAddress = "2001:db8::1".parse().unwrap();
+        let second_address: PeerAddress = "2001:db8::2".parse().unwrap();
+        let (first_transport, second_transport) =
+            LoopbackTransport::pair(first_address, second_address);
+        let mut first = IcmPongConnection::new(second_address, Arc::new(first_transport), None);
+        let mut second = IcmPongConnection::new(
+            first_address,
+            Arc::new(second_transport),
+            Some("hidden".to_string()),
+        );
+        first.enable_encryption();
+        second.enable_encryption();
+
+        first.handle(SessionEvent::Connect).unwrap();
+        let (ping, _) = second.transport.receive_from().unwrap();
+        assert!(!Encryption::is_sealed(&ping.payload));
+        let ping = second.decode(&ping).unwrap();
+        second.handle(SessionEvent::Received(ping)).unwrap();
+
+        let (ready, _) = first.transport.receive_from().unwrap();
+        assert!(Encryption::is_sealed(&ready.payload));
+        assert!(!ready.payload.windows(6).any(|window| window == b"hidden"));
+        assert_eq!(
+            IcmPongPacket::decode_echo_request(&ready, None),
+            Err(DecodeError::EncryptionRequired)
+        );
+        let ready = first.decode(&ready).unwrap();
+        first.handle(SessionEvent::Received(ready)).unwrap();
+        assert!(first.session.is_established());
+        assert_eq!(
+            first.session.peer().unwrap().name.as_deref(),
+            Some("hidden")
+        );
+
+        // anything else in the clear is dropped once encrypting
+        let start = OwnedPacket {
+            session_id: first.session.session_id(),
+            ..IcmPongPacket::decode(&IcmPongPacket::Start.encode(PROTOCOL_VERSION, 1)).unwrap()
+        };
+        assert_eq!(
+            first.decode(&start.to_echo_request(1, None)),
+            Err(DecodeError::Unauthenticated)
+        );
+    }
+
+    #[test]
+    fn only_the_established_peers_key_is_pinned() {
+        let first_address: PeerAddress = "2001:db8::1".parse().unwrap();
+        let second_address: PeerAddress = "2001:db8::2".parse().unwrap();
+        let (first_transport, second_transport) =
+            LoopbackTransport::pair(first_address, second_address);
+        let mut first = IcmPongConnection::new(second_address, Arc::new(first_transport), None);
+        let mut second = IcmPongConnection::new(first_address, Arc::new(second_transport), None);
+        let (mallory_transport, mallory_peer) =
+            LoopbackTransport::pair(second_address, first_address);
+        let mut mallory = IcmPongConnection::new(first_address, Arc::new(mallory_transport), None);
+        for connection in [&mut first, &mut second, &mut mallory] {
+            connection.enable_encryption();
+        }
+        let public_key =
+            |connection: &IcmPongConnection| connection.encryption.as_ref().unwrap().public_key();
+
+        // a Ping from someone else is answered, sealed for them
+        mallory.handle(SessionEvent::Connect).unwrap();
+        let (ping, _) = mallory_peer.receive_from().unwrap();
+        let ping = first.decode(&ping).unwrap();
+        first.handle(SessionEvent::Received(ping)).unwrap();
+        assert_eq!(first.peer_public_key, None);
+        let (ready, _) = second.transport.receive_from().unwrap();
+        assert!(mallory.decode(&ready).is_ok());
+        assert_eq!(second.decode(&ready), Err(DecodeError::Unauthenticated));
+
+        // but the key only sticks once a Ready echoes our cookie
+        first.handle(SessionEvent::Connect).unwrap();
+        let (ping, _) = second.transport.receive_from().unwrap();
+        let ping = second.decode(&ping).unwrap();
+        second.handle(SessionEvent::Received(ping)).unwrap();
+        let (ready, _) = first.transport.receive_from().unwrap();
+        let ready = first.decode(&ready).unwrap();
+        first.handle(SessionEvent::Received(ready)).unwrap();
+        assert!(first.session.is_established());
+        assert_eq!(first.peer_public_key, Some(public_key(&second)));
+        assert!(first.candidate_keys.is_empty());
+        let (ack, _) = second.transport.receive_from().unwrap();
+        assert!(second.decode(&ack).is_ok());
+    }
+
+    #[test]
+    fn packets_survive_encode_and_decode() {
+        let packets = [
+            IcmPongPacket::Ping(Ping::new(Handshake::local())),
+            IcmPongPacket::Ping(Ping {
+                handshake: Handshake::local(),
+                game: game_tag("game"),
+            }),
+            IcmPongPacket::Ready(Ready {
+                handshake: Handshake::local(),
+                name: Some("player".to_string()),
+            }),
+            IcmPongPacket::Ready(Ready {
+                handshake: Handshake::local(),
+                name: None,
+            }),
+            IcmPongPacket::PaddlePosition(PaddlePosition {
+                x_position: 3,
+                y_position: 10,
+            }),
+            IcmPongPacket::BallUpdate(BallUpdate {
+                x_position: 40,
+                y_position: 12,
+                x_movement: 0.5,
+                y_movement: -0.25,
+                xf32: 40.5,
+                yf32: 12.75,
+            }),
+            IcmPongPacket::ScoreUpdate(ScoreUpdate { score: [3, 7] }),
+            IcmPongPacket::Disconnect,
+            IcmPongPacket::Heartbeat,
+            IcmPongPacket::Ack(Ack { sequence: 12 }),
+        ];
+        for packet in packets {
+            let decoded = IcmPongPacket::decode(&packet.encode(PROTOCOL_VERSION, 1234)).unwrap();
+            assert_eq!(decoded.client_id, 1234);
+            assert_eq!(decoded.packet, packet);
+        }
+
+        // long names are cut off between characters
+        let name = "é".repeat(MAXIMUM_NAME_LENGTH);
+        let ready = IcmPongPacket::Ready(Ready {
+            handshake: Handshake::local(),
+            name: Some(name.clone()),
+        });
+        let decoded = IcmPongPacket::decode(&ready.encode(PROTOCOL_VERSION, 1)).unwrap();
+        let IcmPongPacket::Ready(ready) = decoded.packet else {
+            panic!("not a Ready: {:?}", decoded.packet);
+        };
+        assert_eq!(ready.name, Some("é".repeat(MAXIMUM_NAME_LENGTH / 2)));
+    }
+
+    #[test]
+    fn malformed_packets_are_errors() {
+        let mut packet = IcmPongPacket::Ready(Ready {
+            handshake: Handshake::local(),
+            name: None,
+        })
+        .encode(PROTOCOL_VERSION, 1);
+        assert_eq!(
+            IcmPongPacket::decode(&packet[..20]),
+            Err(DecodeError::InvalidSize(20))
+        );
+        assert_eq!(IcmPongPacket::decode(b"ICM"), Err(DecodeError::NotIcmPong));
+
+        let name_offset = 13 + HANDSHAKE_SIZE;
+        packet[name_offset] = MAXIMUM_NAME_LENGTH as u8 + 1;
+        assert_eq!(
+            IcmPongPacket::decode(&packet),
+            Err(DecodeError::NameTooLong(MAXIMUM_NAME_LENGTH + 1))
+        );
+        packet[name_offset] = 1;
+        packet[name_offset + 1] = 0xff;
+        assert!(matches!(
+            IcmPongPacket::decode(&packet),
+            Err(DecodeError::InvalidName(_))
+        ));
+        packet[12] = 200;
+        assert_eq!(
+            IcmPongPacket::decode(&packet),
+            Err(DecodeError::UnknownPacketType(200))
+        );
+
+        let mut ping =
+            IcmPongPacket::Ping(Ping::new(Handshake::local())).encode(PROTOCOL_VERSION, 1);
+        ping[13] = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            IcmPongPacket::decode(&ping),
+            Err(DecodeError::InvalidField("version range"))
+        );
+
+        let mut ball = IcmPongPacket::BallUpdate(BallUpdate {
+            x_position: 1,
+            y_position: 1,
+            x_movement: 0.5,
+            y_movement: 0.5,
+            xf32: 1.0,
+            yf32: 1.0,
+        })
+        .encode(PROTOCOL_VERSION, 1);
+        ball[13 + 4..13 + 8].copy_from_slice(&f32::NAN.to_be_bytes());
+        assert_eq!(
+            IcmPongPacket::decode(&ball),
+            Err(DecodeError::InvalidField("ball movement"))
+        );
+        ball[13 + 4..13 + 8].copy_from_slice(&0.5f32.to_be_bytes());
+        ball[13 + 16..13 + 20].copy_from_slice(&f32::INFINITY.to_be_bytes());
+        assert_eq!(
+            IcmPongPacket::decode(&ball),
+            Err(DecodeError::InvalidField("ball position"))
+        );
+    }
+
+    /// Valid packets of every type and a few versions, which the fuzz test
+    /// below starts from.
+    fn corpus() -> Vec<Vec<u8>> {
+        let packets = [
+            IcmPongPacket::Ping(Ping::new(Handshake::local())),
+            IcmPongPacket::Ready(Ready {
+                handshake: Handshake::local(),
+                name: Some("player".to_string()),
+            }),
+            IcmPongPacket::Start,
+            IcmPongPacket::PaddlePosition(PaddlePosition {
+                x_position: 3,
+                y_position: 10,
+            }),
+            IcmPongPacket::BallUpdate(BallUpdate {
+                x_position: 40,
+                y_position: 12,
+                x_movement: 0.5,
+                y_movement: -0.25,
+                xf32: 40.5,
+                yf32: 12.75,
+            }),
+            IcmPongPacket::ScoreUpdate(ScoreUpdate { score: [3, 7] }),
+            IcmPongPacket::Disconnect,
+            IcmPongPacket::Heartbeat,
+            IcmPongPacket::Ack(Ack { sequence: 12 }),
+        ];
+        let mut corpus = Vec::new();
+        for packet in packets {
+            for version in [LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION, 200] {
+                let mut encoded = packet.encode(version, 1);
+                if version == LEGACY_PROTOCOL_VERSION {
+                    encoded.truncate(FROZEN_PACKET_SIZE);
+                }
+                corpus.push(encoded);
+            }
+        }
+        corpus
+    }
+
+    #[test]
+    fn decoder_survives_mutated_packets() {
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0x1c3b);
+        let key = SecretKey::derive("fuzz");
+        let (transport, _) =
+            LoopbackTransport::pair("::1".parse().unwrap(), "::2".parse().unwrap());
+        let mut plain = IcmPongConnection::new("::2".parse().unwrap(), Arc::new(transport), None);
+        let (transport, _) =
+            LoopbackTransport::pair("::1".parse().unwrap(), "::2".parse().unwrap());
+        let mut encrypted =
+            IcmPongConnection::new("::2".parse().unwrap(), Arc::new(transport), None);
+        encrypted.enable_encryption();
+        encrypted.key = Some(key.clone());
+
+        // envelopes from someone else, whose key we haven't pinned
+        let public_key = encrypted.encryption.as_ref().unwrap().public_key();
+        let mut seeds = corpus();
+        seeds.extend(
+            corpus()
+                .iter()
+                .filter(|packet| packet[7] == PROTOCOL_VERSION)
+                .map(|packet| Encryption::new().seal(&public_key, 1, packet)),
+        );
+        let mut inputs = Vec::new();
+        for packet in seeds {
+            // every truncation, and every byte set to a few interesting values
+            for length in 0..=packet.len() + 40 {
+                let mut truncated = packet.clone();
+                truncated.resize(length, 0);
+                inputs.push(truncated);
+            }
+            for index in 0..packet.len() {
+                for value in [0, 0x80, 0xff, rng.gen()] {
+                    let mut mutated = packet.clone();
+                    mutated[index] = value;
+                    inputs.push(mutated);
+                }
+            }
+            for _ in 0..50 {
+                let mut mutated = packet.clone();
+                for _ in 0..rng.gen_range(1..8) {
+                    let index = rng.gen_range(0..mutated.len());
+                    mutated[index] = rng.gen();
+                }
+                inputs.push(mutated);
+            }
+        }
+        for _ in 0..500 {
+            let length = rng.gen_range(0..PACKET_SIZE * 2);
+            let mut random: Vec<u8> = (0..length).map(|_| rng.gen()).collect();
+            if rng.gen() {
+                random.splice(..length.min(PACKET_MAGIC.len()), *PACKET_MAGIC);
+            }
+            inputs.push(random);
+        }
+
+        for input in inputs {
+            if let Ok(packet) = IcmPongPacket::decode(&input) {
+                if let IcmPongPacket::BallUpdate(ball) = packet.packet {
+                    assert!(ball.x_movement.is_finite() && ball.xf32.is_finite());
+                }
+            }
+            let request = EchoRequest {
+                identifier: rng.gen(),
+                sequence: rng.gen(),
+                payload: input,
+            };
+            let _ = IcmPongPacket::decode_echo_request(&request, None);
+            let _ = plain.decode(&request);
+            let _ = encrypted.decode(&request);
+            let mut signed = request.clone();
+            signed
+                .payload
+                .extend_from_slice(&key.sign(&request.payload));
+            let _ = IcmPongPacket::decode_echo_request(&signed, Some(&key));
+            let _ = encrypted.decode(&signed);
+        }
+    }
+
+    #[test]
+    fn fields_use_network_byte_order() {
+        let packet = IcmPongPacket::PaddlePosition(PaddlePosition {
+            x_position: 0x0102,
+            y_position: 0x0304,
+        })
+        .encode(PROTOCOL_VERSION, 0x0a0b0c0d);
+        assert_eq!(packet[7], PROTOCOL_VERSION);
+        assert_eq!(packet[8..12], [0x0a, 0x0b, 0x0c, 0x0d]);
+        assert_eq!(packet[13..17], [0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(packet[FROZEN_PACKET_SIZE..], [0; 18]);
+
+        let packet = OwnedPacket {
+            version: PROTOCOL_VERSION,
+            client_id: 1,
+            sequence: 0x05060708,
+            timestamp: 0x090a0b0c,
+            session_id: 0x0d0e,
+            cookie: 0x0f101112,
+            token: 0x13141516,
+            packet: IcmPongPacket::Start,
+        };
+        let encoded = packet.encode();
+        assert_eq!(encoded.len(), PACKET_SIZE);
+        assert_eq!(
+            encoded[FROZEN_PACKET_SIZE..],
+            [
+                0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f, 0x10, 0x11, 0x12,
+                0x13, 0x14, 0x15, 0x16
+            ]
+        );
+        assert_eq!(IcmPongPacket::decode(&encoded), Ok(packet));
+    }
+
+    #[test]
+    fn echo_requests_carry_the_session_id() {
+        let packet = OwnedPacket {
+            version: PROTOCOL_VERSION,
+            client_id: 1,
+            sequence: 0,
+            timestamp: 0,
+            session_id: 0x0102,
+            cookie: 0,
+            token: 0,
+            packet: IcmPongPacket::Heartbeat,
+        };
+        let mut request = packet.to_echo_request(5, None);
+        assert_eq!(request.identifier, 0x0102);
+        assert_eq!(request.sequence, 5);
+        assert_eq!(request.payload, packet.encode());
+        assert_eq!(
+            IcmPongPacket::decode_echo_request(&request, None),
+            Ok(packet)
+        );
+
+        request.identifier = 0x0103;
+        assert_eq!(
+            IcmPongPacket::decode_echo_request(&request, None),
+            Err(DecodeError::IdentifierMismatch(0x0103))
+        );
+
+        // before version 7, the magic started right after the checksum
+        let old = IcmPongPacket::Start.encode(6, 1);
+        let request = EchoRequest {
+            identifier: u16::from_be_bytes([old[0], old[1]]),
+            sequence: u16::from_be_bytes([old[2], old[3]]),
+            payload: old[4..].to_vec(),
+        };
+        assert_eq!(
+            IcmPongPacket::decode_echo_request(&request, None),
+            Err(DecodeError::VersionMismatch(6))
+        );
+    }
+
+    #[test]
+    fn packets_are_authenticated_with_the_secret() {
+        let key = SecretKey::derive("secret");
+        let packet = IcmPongPacket::Disconnect.encode(PROTOCOL_VERSION, 1);
+        let packet = IcmPongPacket::decode(&packet).unwrap();
+        let mut request = packet.to_echo_request(1, Some(&key));
+        assert_eq!(request.payload.len(), PACKET_SIZE + MAC_SIZE);
+        assert_eq!(
+            IcmPongPacket::decode_echo_request(&request, Some(&key)),
+            Ok(packet.clone())
+        );
+        assert_eq!(
+            IcmPongPacket::decode_echo_request(&request, Some(&SecretKey::derive("guess"))),
+            Err(DecodeError::Unauthenticated)
+        );
+        assert_eq!(
+            IcmPongPacket::decode_echo_request(&request, None),
+            Err(DecodeError::SecretRequired)
+        );
+        request.payload[12] = IcmPongPacketType::Start as u8;
+        assert_eq!(
+            IcmPongPacket::decode_echo_request(&request, Some(&key)),
+            Err(DecodeError::Unauthenticated)
+        );
+
+        let request = packet.to_echo_request(2, None);
+        assert_eq!(
+            IcmPongPacket::decode_echo_request(&request, Some(&key)),
+            Err(DecodeError::Unauthenticated)
+        );
+    }
+
+    #[test]
+    fn legacy_packets_are_decoded_with_their_version() {
+        let mut packet = [PACKET_MAGIC.as_slice(), &[LEGACY_PROTOCOL_VERSION]].concat();
+        packet.extend_from_slice(&42u32.to_ne_bytes());
+        packet.push(IcmPongPacketType::Ready as u8);
+        packet.extend_from_slice(&[0; PACKET_DATA_SIZE]);
+        packet[13] = 3;
+        packet[14..17].copy_from_slice(b"old");
+
+        let decoded = IcmPongPacket::decode(&packet).unwrap();
+        assert_eq!(decoded.version, LEGACY_PROTOCOL_VERSION);
+        assert_eq!(decoded.client_id, 42);
+        let IcmPongPacket::Ready(ready) = decoded.packet else {
+            panic!("expected a Ready packet");
+        };
+        assert_eq!(ready.name.as_deref(), Some("old"));
+        assert_eq!(Handshake::local().negotiate(&ready.handshake), None);
+    }
+
+    #[test]
+    fn newer_versions_can_still_negotiate() {
+        let newer = Handshake {
+            minimum_version: MINIMUM_PROTOCOL_VERSION,
+            maximum_version: 200,
+            features: u32::MAX,
+        };
+        let mut packet = IcmPongPacket::Ping(Ping::new(newer)).encode(200, 1);
+        packet.extend_from_slice(&[0; 8]);
+        let decoded = IcmPongPacket::decode(&packet).unwrap();
+        assert_eq!(decoded.packet, IcmPongPacket::Ping(Ping::new(newer)));
+        assert_eq!(
+            Handshake::local().negotiate(&newer),
+            Some((PROTOCOL_VERSION, FEATURES))
+        );
+
+        let start = IcmPongPacket::Start.encode(200, 1);
+        assert_eq!(
+            IcmPongPacket::decode(&start),
+            Err(DecodeError::VersionMismatch(200))
+        );
+
+        let too_new = Handshake {
+            minimum_version: PROTOCOL_VERSION + 1,
+            ..newer
+        };
+        assert_eq!(Handshake::local().negotiate(&too_new), None);
     }
 }
@@ -1,3 +1,4 @@
+mod privileges;
 mod tui;
 
 use crate::tui::{
@@ -7,22 +8,50 @@ use clap::Parser;
 use crossterm::event::{poll, Event, KeyCode};
 use crossterm::style::{Color, Print, SetBackgroundColor, SetForegroundColor};
 use crossterm::{cursor, terminal, ExecutableCommand};
-use icmpong::{IcmPongConnection, IcmPongError, IcmPongPacket, IcmPongPacketType};
-use pnet::transport::TransportReceiver;
-use pnet::{packet::Packet, transport::icmpv6_packet_iter};
+use icmpong::auth::SecretKey;
+use icmpong::ratelimit;
+use icmpong::session::{self, SessionEvent, SessionState};
+use icmpong::transport::{EchoRequest, IcmpTransport, PeerAddress, Transport};
+use icmpong::{
+    BallUpdate, DecodeError, IcmPongConnection, IcmPongError, IcmPongPacket, PaddlePosition,
+    ScoreUpdate,
+};
 use rand::Rng;
 use std::io::stdout;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender, TryRecvError};
 use std::sync::{Arc, Mutex};
-use std::{io::Write, net::Ipv6Addr, str::FromStr, time::Duration};
+use std::{
+    io::Write,
+    time::{Duration, Instant},
+};
+
+/// How long to wait for an answer to the first Ping before sending another.
+/// The wait doubles after every Ping, up to [`MAXIMUM_PING_INTERVAL`].
+const PING_INTERVAL: Duration = Duration::from_millis(250);
+const MAXIMUM_PING_INTERVAL: Duration = Duration::from_secs(4);
+/// How long to wait for the peer to acknowledge our Disconnect when quitting.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long the connection thread waits for a packet before ticking the
+/// session and checking whether it should stop.
+const RECEIVE_TIMEOUT: Duration = Duration::from_millis(100);
+/// How often rejected packets are reported to the game loop at most, so that
+/// a flood of them doesn't turn into a flood of events.
+const REJECTION_REPORT_INTERVAL: Duration = Duration::from_millis(100);
 
 /// The classic game of Pong, in your terminal, over ICMPv6!
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Arguments {
-    /// The IPv6 address of person you want to play ICMPong with
+    /// The IPv6 or IPv4 address (or hostname) of person you want to play ICMPong with
+    /// (link-local addresses need a zone, like fe80::1%eth0, or --interface)
     #[arg(short, long)]
     peer: String,
 
+    /// The network interface to play over
+    #[arg(short, long)]
+    interface: Option<String>,
+
     /// The name you want the other person to see
     #[arg(short, long)]
     name: Option<String>,
@@ -30,111 +59,236 @@ struct Arguments {
     /// The initial ball velocity (will slowly increase after each bounce)
     #[arg(short, long, default_value_t = 0.6)]
     ball_velocity: f32,
+
+    /// How many seconds to wait for the other person before giving up
+    #[arg(long, default_value_t = 30)]
+    connect_timeout: u64,
+
+    /// How many seconds the other person may stop responding for before the game ends (more
+    /// than the 2 after which the connection is shown as lost)
+    #[arg(
+        long,
+        default_value_t = 10,
+        value_parser = clap::value_parser!(u64).range(session::LOST_AFTER.as_secs() + 1..)
+    )]
+    peer_timeout: u64,
+
+    /// A passphrase to authenticate every packet with (the other person has to use the same one)
+    #[arg(long)]
+    secret: Option<String>,
+
+    /// Encrypt everything sent after the handshake (the other person has to as well). Combine
+    /// with --secret, or someone in the middle could still read along
+    #[arg(long)]
+    encrypt: bool,
+
+    /// The name of the game to join, to play more than one between the same hosts (the other
+    /// person has to use the same one)
+    #[arg(long)]
+    game: Option<String>,
+}
+
+/// What the connection thread tells the game loop, which owns all of the
+/// game's state and applies these once per tick.
+enum NetEvent {
+    /// The peer moved its paddle.
+    PaddlePosition(PaddlePosition),
+    /// The host changed the ball's course.
+    BallUpdate(BallUpdate),
+    ScoreUpdate(ScoreUpdate),
+    /// The session moved on, because of the peer, a timeout or us.
+    State(SessionState),
+    /// How long is left until the session times out, while the peer is
+    /// silent. Sent again whenever the whole seconds left change.
+    ConnectionLost(Option<Duration>),
+    /// `count` more packets were rejected, the last one for `reason`.
+    Rejected {
+        count: u64,
+        reason: String,
+    },
+    /// Progress to show while connecting.
+    Status(String),
+    /// The connection thread stopped, for this reason.
+    Failed(String),
 }
 
 fn main() -> anyhow::Result<()> {
     let arguments = Arguments::parse();
-    let ipv6_address = match Ipv6Addr::from_str(&arguments.peer) {
-        Ok(ipv6_address) => ipv6_address,
+    let mut peer_addresses = match PeerAddress::resolve(&arguments.peer) {
+        Ok(peer_addresses) => peer_addresses,
         Err(error) => {
-            eprintln!("unable to parse IPv6 address: {error}");
+            eprintln!("unable to parse peer address: {error}");
             return Ok(());
         }
     };
-    if let Some(ref name) = arguments.name {
-        if name.len() > 30 {
-            eprintln!("your name must not be longer than 30 characters!");
-            return Ok(());
-        }
+    if let Some(ref interface) = arguments.interface {
+        peer_addresses = match peer_addresses
+            .into_iter()
+            .map(|peer_address| peer_address.with_interface(interface))
+            .collect()
+        {
+            Ok(peer_addresses) => peer_addresses,
+            Err(error) => {
+                eprintln!("unable to use interface: {error}");
+                return Ok(());
+            }
+        };
     }
-
-    println!("establishing connection with {ipv6_address}...");
-    let (connection, mut rx) = match IcmPongConnection::new(ipv6_address) {
-        Ok((connection, rx)) => (Arc::new(Mutex::new(connection)), rx),
-        Err(error) => {
-            eprintln!("unable to create IPv6 socket: {error:?}");
-            return Ok(());
+    let unscoped_address = peer_addresses
+        .iter()
+        .find(|peer_address| peer_address.is_link_local() && peer_address.scope_id == 0)
+        .copied();
+    peer_addresses
+        .retain(|peer_address| !peer_address.is_link_local() || peer_address.scope_id != 0);
+    if peer_addresses.is_empty() {
+        match unscoped_address {
+            Some(peer_address) => eprintln!(
+                "link-local addresses need an interface, like {peer_address}%eth0 or --interface eth0"
+            ),
+            None => eprintln!("{} has no addresses to connect to", arguments.peer),
         }
-    };
-    println!("sending Ping packet...");
-    match connection
-        .lock()
-        .unwrap()
-        .send_packet(IcmPongPacket::new(IcmPongPacketType::Ping, &[0; 32]))
-    {
-        Ok(_) => (),
-        Err(error) => {
-            eprintln!("unable to send Ping packet: {error:?}");
+        return Ok(());
+    }
+    if let Some(ref name) = arguments.name {
+        if name.len() > icmpong::MAXIMUM_NAME_LENGTH {
+            eprintln!(
+                "your name must not be longer than {} bytes!",
+                icmpong::MAXIMUM_NAME_LENGTH
+            );
             return Ok(());
         }
     }
 
-    let self_name = arguments.name.clone();
-    let stop_game = Arc::new(Mutex::new(false));
-    let connection_established = Arc::new(Mutex::new(false));
-    let peer_client_id = Arc::new(Mutex::new(None));
-    let peer_start_game = Arc::new(Mutex::new(false));
-    let ball = Arc::new(Mutex::new(GameObject::new(
-        X_MAXIMUM / 2,
-        Y_MAXIMUM / 2,
-        1,
-        b'O',
+    println!(
+        "establishing connection with {}...",
+        peer_addresses
+            .iter()
+            .map(|peer_address| peer_address.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    let transport: Arc<dyn Transport> =
+        match IcmpTransport::new(&peer_addresses, arguments.interface.as_deref()) {
+            Ok(transport) => Arc::new(transport),
+            Err(error) => {
+                eprintln!("unable to create ICMP socket: {error}");
+                if error.kind() == std::io::ErrorKind::PermissionDenied {
+                    print_permission_help();
+                }
+                return Ok(());
+            }
+        };
+    if let Err(error) = privileges::drop_privileges() {
+        eprintln!("unable to drop privileges after creating the ICMP socket: {error}");
+        return Ok(());
+    }
+    let connection = Arc::new(Mutex::new(IcmPongConnection::new(
+        peer_addresses[0],
+        transport.clone(),
+        arguments.name.clone(),
     )));
-    let peer_player = Arc::new(Mutex::new(None));
-    let peer_name = Arc::new(Mutex::new(None));
-    let score = Arc::new(Mutex::new([0, 0]));
+    // the first address to answer becomes the peer
+    connection.lock().unwrap().candidates = peer_addresses.clone();
+    connection.lock().unwrap().session.peer_timeout = Duration::from_secs(arguments.peer_timeout);
+    connection.lock().unwrap().key = arguments.secret.as_deref().map(SecretKey::derive);
+    connection.lock().unwrap().session.game = arguments
+        .game
+        .as_deref()
+        .map(icmpong::game_tag)
+        .unwrap_or(0);
+    if arguments.encrypt {
+        connection.lock().unwrap().enable_encryption();
+    }
+
     let thread_connection = connection.clone();
-    let thread_stop_game = stop_game.clone();
-    let thread_connnection_established = connection_established.clone();
-    let thread_peer_client_id = peer_client_id.clone();
-    let thread_peer_start_game = peer_start_game.clone();
-    let thread_ball = ball.clone();
-    let thread_peer_player = peer_player.clone();
-    let thread_peer_name = peer_name.clone();
-    let thread_score = score.clone();
+    let (events_tx, events) = channel();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let thread_shutdown = shutdown.clone();
     let connection_thread = std::thread::spawn(move || {
-        connection_loop(
-            self_name,
-            thread_connection,
-            &mut rx,
-            thread_stop_game,
-            thread_connnection_established,
-            thread_peer_client_id,
-            thread_peer_start_game,
-            thread_ball,
-            thread_peer_player,
-            thread_peer_name,
-            thread_score,
-        )
+        connection_loop(thread_connection, transport, events_tx, thread_shutdown)
     });
 
-    while !*connection_established.lock().unwrap() {
-        std::thread::sleep(Duration::from_millis(1))
+    let connect_timeout = Duration::from_secs(arguments.connect_timeout);
+    let connect_start = Instant::now();
+    let mut schedule = PingSchedule::new(connect_start, connect_timeout);
+    let mut shown_status = None;
+    let mut state = SessionState::Idle;
+    let mut rejected_packets = 0;
+    let mut rejection = None;
+    // game updates that arrive before the game starts are kept for it
+    let mut early_events = Vec::new();
+    loop {
+        loop {
+            match events.try_recv() {
+                Ok(NetEvent::State(new_state)) => state = new_state,
+                Ok(NetEvent::Rejected { count, reason }) => {
+                    rejected_packets += count;
+                    rejection = Some(reason);
+                }
+                Ok(NetEvent::Status(message)) => print_status(&message),
+                Ok(NetEvent::Failed(message)) => {
+                    print_status("");
+                    eprintln!("{message}");
+                    return Ok(());
+                }
+                Ok(event) => early_events.push(event),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        }
+        if state.is_established() {
+            break;
+        }
+        let elapsed = connect_start.elapsed();
+        if schedule.timed_out(Instant::now()) {
+            print_status("");
+            eprintln!(
+                "the other player didn't answer within {} seconds!",
+                connect_timeout.as_secs()
+            );
+            // someone did, but we couldn't have played with them
+            let connection = connection.lock().unwrap();
+            if let Some(error) = connection.session.incompatibility() {
+                eprintln!("{}", error_message(error, connection.session.version()));
+            }
+            return Ok(());
+        }
+        if schedule.ping_due(Instant::now()) {
+            if let Err(error) = connection.lock().unwrap().handle(SessionEvent::Connect) {
+                print_status("");
+                eprintln!("unable to send Ping packet: {error:?}");
+                return Ok(());
+            }
+        }
+        if shown_status != Some((elapsed.as_secs(), rejected_packets)) {
+            shown_status = Some((elapsed.as_secs(), rejected_packets));
+            print_status("");
+            print!("waiting for peer... ({}s)", elapsed.as_secs());
+            if let Some(ref rejection) = rejection {
+                print!(" (rejected {rejected_packets} packets, last one: {rejection})");
+            }
+            stdout().flush()?;
+        }
+        std::thread::sleep(Duration::from_millis(50))
     }
-    let self_is_host =
-        connection.lock().unwrap().client_id > peer_client_id.lock().unwrap().unwrap();
-    let player1 = Arc::new(Mutex::new(GameObject::new(
-        X_MINIMUM + 3,
-        (Y_MAXIMUM - Y_MINIMUM) / 2 - 1,
-        4,
-        b'X',
-    )));
-    let player2 = Arc::new(Mutex::new(GameObject::new(
-        X_MAXIMUM - 4,
-        (Y_MAXIMUM - Y_MINIMUM) / 2 - 1,
-        4,
-        b'X',
-    )));
-    *peer_player.lock().unwrap() = Some(if self_is_host {
-        player2.clone()
-    } else {
-        player1.clone()
-    });
+    print_status("");
+    let (self_is_host, peer_name) = {
+        let session = &connection.lock().unwrap().session;
+        let peer = session.peer().unwrap();
+        let peer_name = peer.name.as_deref().map(tui::sanitize);
+        (session.client_id() > peer.client_id, peer_name)
+    };
+    let mut ball = GameObject::new(X_MAXIMUM / 2, Y_MAXIMUM / 2, 1, b'O');
+    let mut player1 = GameObject::new(X_MINIMUM + 3, (Y_MAXIMUM - Y_MINIMUM) / 2 - 1, 4, b'X');
+    let mut player2 = GameObject::new(X_MAXIMUM - 4, (Y_MAXIMUM - Y_MINIMUM) / 2 - 1, 4, b'X');
+    let mut score = [0, 0];
+    let mut connection_lost = None;
+    let mut status = None;
+    let mut failure = None;
 
     let mut field = Field::new();
     let mut game_started = false;
     let mut round_winner = 0;
-    let mut self_started_game = false;
     let mut tick_counter = 0;
     let mut bounces = 0;
 
@@ -150,7 +304,44 @@ fn main() -> anyhow::Result<()> {
     'game_loop: loop {
         tick_counter += 1;
 
-        if *stop_game.lock().unwrap() {
+        let mut tick_events = std::mem::take(&mut early_events);
+        tick_events.extend(events.try_iter());
+        for event in tick_events {
+            let peer_player = if self_is_host {
+                &mut player2
+            } else {
+                &mut player1
+            };
+            if let Err(reason) = check_update(&event, peer_player) {
+                rejected_packets += 1;
+                rejection = Some(reason.to_string());
+                continue;
+            }
+            match event {
+                NetEvent::PaddlePosition(paddle) => peer_player.y_position = paddle.y_position,
+                NetEvent::BallUpdate(ball_update) => {
+                    ball.x_position = ball_update.x_position;
+                    ball.y_position = ball_update.y_position;
+                    ball.x_movement = ball_update.x_movement;
+                    ball.y_movement = ball_update.y_movement;
+                    ball.xf32 = ball_update.xf32;
+                    ball.yf32 = ball_update.yf32;
+                }
+                NetEvent::ScoreUpdate(score_update) => score = score_update.score,
+                NetEvent::State(new_state) => state = new_state,
+                NetEvent::ConnectionLost(remaining) => connection_lost = remaining,
+                NetEvent::Rejected { count, reason } => {
+                    rejected_packets += count;
+                    rejection = Some(reason);
+                }
+                NetEvent::Status(message) => status = Some(message),
+                NetEvent::Failed(message) => {
+                    failure = Some(message);
+                    break 'game_loop;
+                }
+            }
+        }
+        if state == SessionState::Closing {
             break 'game_loop;
         }
 
@@ -160,19 +351,12 @@ fn main() -> anyhow::Result<()> {
             if event == Event::Key(KeyCode::Esc.into())
                 || event == Event::Key(KeyCode::Char('q').into())
             {
-                let _ = connection
-                    .lock()
-                    .unwrap()
-                    .send_packet(IcmPongPacket::new(IcmPongPacketType::Disconnect, &[0; 32]));
+                let _ = connection.lock().unwrap().handle(SessionEvent::Quit);
                 break 'game_loop;
             }
 
             if event == Event::Key(KeyCode::Char(' ').into()) {
-                match connection
-                    .lock()
-                    .unwrap()
-                    .send_packet(IcmPongPacket::new(IcmPongPacketType::Start, &[0; 32]))
-                {
+                match connection.lock().unwrap().handle(SessionEvent::Start) {
                     Ok(_) => (),
                     Err(error) => {
                         cleanup()?;
@@ -180,23 +364,14 @@ fn main() -> anyhow::Result<()> {
                         return Ok(());
                     }
                 }
-                self_started_game = true;
             }
 
-            if self_started_game {
+            if matches!(state, SessionState::Lobby | SessionState::InGame) {
                 if event == Event::Key(KeyCode::Up.into()) {
                     if self_is_host {
-                        if player1.lock().unwrap().get_ymin() > Y_MINIMUM + 1 {
-                            player1.lock().unwrap().y_position -= 1;
-                            let mut data = [0; 32];
-                            data[0..2]
-                                .copy_from_slice(&player1.lock().unwrap().x_position.to_ne_bytes());
-                            data[2..4]
-                                .copy_from_slice(&player1.lock().unwrap().y_position.to_ne_bytes());
-                            match connection.lock().unwrap().send_packet(IcmPongPacket::new(
-                                IcmPongPacketType::PaddlePosition,
-                                &data,
-                            )) {
+                        if player1.get_ymin() > Y_MINIMUM + 1 {
+                            player1.y_position -= 1;
+                            match send_paddle_position(&connection, &player1) {
                                 Ok(_) => (),
                                 Err(error) => {
                                     cleanup()?;
@@ -206,17 +381,9 @@ fn main() -> anyhow::Result<()> {
                             };
                         }
                     } else {
-                        if player2.lock().unwrap().get_ymin() > Y_MINIMUM + 1 {
-                            player2.lock().unwrap().y_position -= 1;
-                            let mut data = [0; 32];
-                            data[0..2]
-                                .copy_from_slice(&player2.lock().unwrap().x_position.to_ne_bytes());
-                            data[2..4]
-                                .copy_from_slice(&player2.lock().unwrap().y_position.to_ne_bytes());
-                            match connection.lock().unwrap().send_packet(IcmPongPacket::new(
-                                IcmPongPacketType::PaddlePosition,
-                                &data,
-                            )) {
+                        if player2.get_ymin() > Y_MINIMUM + 1 {
+                            player2.y_position -= 1;
+                            match send_paddle_position(&connection, &player2) {
                                 Ok(_) => (),
                                 Err(error) => {
                                     cleanup()?;
@@ -230,17 +397,9 @@ fn main() -> anyhow::Result<()> {
 
                 if event == Event::Key(KeyCode::Down.into()) {
                     if self_is_host {
-                        if player1.lock().unwrap().get_ymax() < Y_MAXIMUM - 1 {
-                            player1.lock().unwrap().y_position += 1;
-                            let mut data = [0; 32];
-                            data[0..2]
-                                .copy_from_slice(&player1.lock().unwrap().x_position.to_ne_bytes());
-                            data[2..4]
-                                .copy_from_slice(&player1.lock().unwrap().y_position.to_ne_bytes());
-                            match connection.lock().unwrap().send_packet(IcmPongPacket::new(
-                                IcmPongPacketType::PaddlePosition,
-                                &data,
-                            )) {
+                        if player1.get_ymax() < Y_MAXIMUM - 1 {
+                            player1.y_position += 1;
+                            match send_paddle_position(&connection, &player1) {
                                 Ok(_) => (),
                                 Err(error) => {
                                     cleanup()?;
@@ -250,17 +409,9 @@ fn main() -> anyhow::Result<()> {
                             };
                         }
                     } else {
-                        if player2.lock().unwrap().get_ymax() < Y_MAXIMUM - 1 {
-                            player2.lock().unwrap().y_position += 1;
-                            let mut data = [0; 32];
-                            data[0..2]
-                                .copy_from_slice(&player2.lock().unwrap().x_position.to_ne_bytes());
-                            data[2..4]
-                                .copy_from_slice(&player2.lock().unwrap().y_position.to_ne_bytes());
-                            match connection.lock().unwrap().send_packet(IcmPongPacket::new(
-                                IcmPongPacketType::PaddlePosition,
-                                &data,
-                            )) {
+                        if player2.get_ymax() < Y_MAXIMUM - 1 {
+                            player2.y_position += 1;
+                            match send_paddle_position(&connection, &player2) {
                                 Ok(_) => (),
                                 Err(error) => {
                                     cleanup()?;
@@ -274,34 +425,28 @@ fn main() -> anyhow::Result<()> {
             }
         }
 
-        if game_started {
-            let mut xf32 = ball.lock().unwrap().xf32;
-            xf32 += ball.lock().unwrap().x_movement;
-            ball.lock().unwrap().xf32 = xf32;
-            let mut yf32 = ball.lock().unwrap().yf32;
-            yf32 += ball.lock().unwrap().y_movement;
-            ball.lock().unwrap().yf32 = yf32;
-            ball.lock().unwrap().x_position = xf32 as u16;
-            ball.lock().unwrap().y_position = yf32 as u16;
+        if game_started && connection_lost.is_none() {
+            ball.xf32 += ball.x_movement;
+            ball.yf32 += ball.y_movement;
+            ball.x_position = ball.xf32 as u16;
+            ball.y_position = ball.yf32 as u16;
 
             if self_is_host {
-                if ball.lock().unwrap().x_position >= X_MAXIMUM {
+                if ball.x_position >= X_MAXIMUM {
                     round_winner = 1;
                 }
-                if ball.lock().unwrap().x_position <= X_MINIMUM {
+                if ball.x_position == X_MINIMUM {
                     round_winner = 2;
                 }
             }
 
-            if ball.lock().unwrap().get_ymin() <= Y_MINIMUM
-                || ball.lock().unwrap().get_ymax() >= Y_MAXIMUM
-            {
-                ball.lock().unwrap().y_movement *= -1.0;
+            if ball.get_ymin() == Y_MINIMUM || ball.get_ymax() >= Y_MAXIMUM {
+                ball.y_movement *= -1.0;
                 if self_is_host {
                     bounces += 1;
-                    if bounces % 5 == 0 && ball.lock().unwrap().y_movement.abs() <= 1.0 {
-                        ball.lock().unwrap().x_movement *= 1.1;
-                        ball.lock().unwrap().y_movement *= 1.1;
+                    if bounces % 5 == 0 && ball.y_movement.abs() <= 1.0 {
+                        ball.x_movement *= 1.1;
+                        ball.y_movement *= 1.1;
                     }
                     match synchronize_ball(&connection, &ball) {
                         Ok(_) => (),
@@ -314,14 +459,14 @@ fn main() -> anyhow::Result<()> {
                 }
             }
 
-            if (ball.lock().unwrap().x_position == player1.lock().unwrap().x_position
-                && ball.lock().unwrap().y_position >= player1.lock().unwrap().get_ymin()
-                && ball.lock().unwrap().y_position <= player1.lock().unwrap().get_ymax())
-                || (ball.lock().unwrap().x_position == player2.lock().unwrap().x_position
-                    && ball.lock().unwrap().y_position >= player2.lock().unwrap().get_ymin()
-                    && ball.lock().unwrap().y_position <= player2.lock().unwrap().get_ymax())
+            if (ball.x_position == player1.x_position
+                && ball.y_position >= player1.get_ymin()
+                && ball.y_position <= player1.get_ymax())
+                || (ball.x_position == player2.x_position
+                    && ball.y_position >= player2.get_ymin()
+                    && ball.y_position <= player2.get_ymax())
             {
-                ball.lock().unwrap().x_movement *= -1.0;
+                ball.x_movement *= -1.0;
                 if self_is_host {
                     match synchronize_ball(&connection, &ball) {
                         Ok(_) => (),
@@ -334,9 +479,9 @@ fn main() -> anyhow::Result<()> {
                 }
             }
 
-            if ball.lock().unwrap().yf32 > Y_MAXIMUM as f32 {
-                ball.lock().unwrap().yf32 = Y_MAXIMUM as f32 - 1.0;
-                ball.lock().unwrap().y_position = Y_MAXIMUM - 1;
+            if ball.yf32 > Y_MAXIMUM as f32 {
+                ball.yf32 = Y_MAXIMUM as f32 - 1.0;
+                ball.y_position = Y_MAXIMUM - 1;
                 if self_is_host {
                     match synchronize_ball(&connection, &ball) {
                         Ok(_) => (),
@@ -357,33 +502,60 @@ fn main() -> anyhow::Result<()> {
             field.write(
                 X_MAXIMUM / 2 - 5,
                 Y_MINIMUM,
-                format!(" {:02} ", score.lock().unwrap()[0]).as_str(),
+                format!(" {:02} ", score[0]).as_str(),
             );
             field.write(
                 X_MAXIMUM / 2 + 2,
                 Y_MINIMUM,
-                format!(" {:02} ", score.lock().unwrap()[1]).as_str(),
+                format!(" {:02} ", score[1]).as_str(),
             );
             if let Some(ref name) = arguments.name {
-                field.write(X_MINIMUM, Y_MINIMUM, &name);
+                field.write(X_MINIMUM, Y_MINIMUM, name);
             }
-            if let Some(peer_name) = peer_name.lock().unwrap().to_owned() {
-                field.write(X_MAXIMUM - peer_name.len() as u16, Y_MINIMUM, &peer_name);
+            if let Some(ref peer_name) = peer_name {
+                field.write(
+                    X_MAXIMUM - peer_name.chars().count() as u16,
+                    Y_MINIMUM,
+                    peer_name,
+                );
             }
 
-            field.draw(&ball.lock().unwrap());
-            field.draw(&player1.lock().unwrap());
-            field.draw(&player2.lock().unwrap());
+            field.draw(&ball);
+            field.draw(&player1);
+            field.draw(&player2);
+
+            let notice = match (&rejection, &status) {
+                (Some(rejection), _) => Some(format!(" {rejected_packets} rejected: {rejection} ")),
+                (None, Some(status)) => Some(format!(" {status} ")),
+                (None, None) => None,
+            };
+            if let Some(notice) = notice {
+                let message: String = notice
+                    .chars()
+                    .take((X_MAXIMUM - X_MINIMUM - 2) as usize)
+                    .collect();
+                field.write(X_MINIMUM + 1, Y_MAXIMUM - 1, &message);
+            }
 
-            if !game_started {
-                if !self_started_game {
+            if let Some(remaining) = connection_lost {
+                let message = format!(
+                    "Connection lost, waiting {}s...",
+                    remaining.as_secs_f32().ceil()
+                );
+                field.write(
+                    X_MAXIMUM / 2 - message.len() as u16 / 2,
+                    Y_MAXIMUM - 4,
+                    &message,
+                )
+            } else if !game_started {
+                if state == SessionState::ReadyExchanged {
                     let message = "Press SPACE to start the game!";
                     field.write(
                         X_MAXIMUM / 2 - message.len() as u16 / 2,
                         Y_MAXIMUM - 4,
                         message,
                     )
-                } else if !*peer_start_game.lock().unwrap() {
+                } else if state == SessionState::Lobby {
                     let message = "Waiting for peer to press SPACE...";
                     field.write(
                         X_MAXIMUM / 2 - message.len() as u16 / 2,
@@ -394,10 +566,8 @@ fn main() -> anyhow::Result<()> {
                     game_started = true;
                     if self_is_host {
                         let random_angle = rand::thread_rng().gen_range(-45..45) as f32;
-                        ball.lock().unwrap().x_movement =
-                            random_angle.cos() * arguments.ball_velocity;
-                        ball.lock().unwrap().y_movement =
-                            random_angle.sin() * arguments.ball_velocity;
+                        ball.x_movement = random_angle.cos() * arguments.ball_velocity;
+                        ball.y_movement = random_angle.sin() * arguments.ball_velocity;
                         match synchronize_ball(&connection, &ball) {
                             Ok(_) => (),
                             Err(error) => {
@@ -413,7 +583,7 @@ fn main() -> anyhow::Result<()> {
             for i in 0..field.field_data.len() {
                 let x: u16 = i as u16 % X_MAXIMUM;
                 let y: u16 = i as u16 / X_MAXIMUM;
-                let c: char = field.field_data[i] as char;
+                let c: char = field.field_data[i];
 
                 stdout()
                     .execute(cursor::MoveTo(x, y))?
@@ -423,17 +593,14 @@ fn main() -> anyhow::Result<()> {
         }
 
         if self_is_host && round_winner > 0 {
-            score.lock().unwrap()[round_winner - 1] += 1;
+            score[round_winner - 1] += 1;
             round_winner = 0;
-            *ball.lock().unwrap() = GameObject::new(X_MAXIMUM / 2, Y_MAXIMUM / 2, 1, b'O');
+            ball = GameObject::new(X_MAXIMUM / 2, Y_MAXIMUM / 2, 1, b'O');
             bounces = 0;
-            let mut data = [0; 32];
-            data[0..4].copy_from_slice(&score.lock().unwrap()[0].to_ne_bytes());
-            data[4..8].copy_from_slice(&score.lock().unwrap()[1].to_ne_bytes());
             match connection
                 .lock()
                 .unwrap()
-                .send_packet(IcmPongPacket::new(IcmPongPacketType::ScoreUpdate, &data))
+                .send_packet(IcmPongPacket::ScoreUpdate(ScoreUpdate { score }))
             {
                 Ok(_) => (),
                 Err(error) => {
@@ -444,8 +611,8 @@ fn main() -> anyhow::Result<()> {
             }
 
             let random_angle = rand::thread_rng().gen_range(-45..45) as f32;
-            ball.lock().unwrap().x_movement = random_angle.cos() * arguments.ball_velocity;
-            ball.lock().unwrap().y_movement = random_angle.sin() * arguments.ball_velocity;
+            ball.x_movement = random_angle.cos() * arguments.ball_velocity;
+            ball.y_movement = random_angle.sin() * arguments.ball_velocity;
             match synchronize_ball(&connection, &ball) {
                 Ok(_) => (),
                 Err(error) => {
@@ -456,30 +623,155 @@ fn main() -> anyhow::Result<()> {
             }
         }
     }
+    // give the peer a chance to acknowledge our Disconnect
+    let closing_start = Instant::now();
+    while connection.lock().unwrap().session.has_unacknowledged()
+        && closing_start.elapsed() < DISCONNECT_TIMEOUT
+    {
+        let _ = connection.lock().unwrap().handle(SessionEvent::Tick);
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let timed_out = connection.lock().unwrap().session.timed_out();
+    shutdown.store(true, Ordering::Relaxed);
     connection_thread.join().unwrap();
+    cleanup()?;
     stdout().execute(SetBackgroundColor(Color::Reset))?;
+    if timed_out {
+        println!(
+            "\nthe other player stopped responding for {} seconds!",
+            arguments.peer_timeout
+        );
+    }
+    if let Some(message) = failure {
+        eprintln!("\n{message}");
+    }
     println!("\nquitting!");
     Ok(())
 }
 
 fn synchronize_ball(
     connection: &Arc<Mutex<IcmPongConnection>>,
-    ball: &Arc<Mutex<GameObject>>,
+    ball: &GameObject,
 ) -> Result<(), IcmPongError> {
-    let mut data = [0; 32];
-    data[0..2].copy_from_slice(&ball.lock().unwrap().x_position.to_ne_bytes());
-    data[2..4].copy_from_slice(&ball.lock().unwrap().y_position.to_ne_bytes());
-    data[4..8].copy_from_slice(&ball.lock().unwrap().x_movement.to_ne_bytes());
-    data[8..12].copy_from_slice(&ball.lock().unwrap().y_movement.to_ne_bytes());
-    data[12..16].copy_from_slice(&ball.lock().unwrap().xf32.to_ne_bytes());
-    data[16..20].copy_from_slice(&ball.lock().unwrap().yf32.to_ne_bytes());
+    let ball_update = BallUpdate {
+        x_position: ball.x_position,
+        y_position: ball.y_position,
+        x_movement: ball.x_movement,
+        y_movement: ball.y_movement,
+        xf32: ball.xf32,
+        yf32: ball.yf32,
+    };
     connection
         .lock()
         .unwrap()
-        .send_packet(IcmPongPacket::new(IcmPongPacketType::BallUpdate, &data))?;
+        .send_packet(IcmPongPacket::BallUpdate(ball_update))?;
     Ok(())
 }
 
+fn send_paddle_position(
+    connection: &Arc<Mutex<IcmPongConnection>>,
+    player: &GameObject,
+) -> Result<(), IcmPongError> {
+    let paddle_position = PaddlePosition {
+        x_position: player.x_position,
+        y_position: player.y_position,
+    };
+    connection
+        .lock()
+        .unwrap()
+        .send_packet(IcmPongPacket::PaddlePosition(paddle_position))
+}
+
+/// Checks a game update from the peer against the field, so that nothing it
+/// sends can end up outside of it.
+fn check_update(event: &NetEvent, peer_player: &GameObject) -> Result<(), &'static str> {
+    match event {
+        // the paddle can only move up and down, and not into the border
+        NetEvent::PaddlePosition(paddle)
+            if paddle.x_position != peer_player.x_position
+                || paddle.y_position == Y_MINIMUM
+                || paddle.y_position.saturating_add(peer_player.size) >= Y_MAXIMUM =>
+        {
+            Err("paddle position outside the field")
+        }
+        // the ball can overshoot the border a little before bouncing or
+        // scoring
+        NetEvent::BallUpdate(ball)
+            if ball.x_position > X_MAXIMUM
+                || ball.y_position > Y_MAXIMUM
+                || (ball.xf32 - ball.x_position as f32).abs() > 2.0
+                || (ball.yf32 - ball.y_position as f32).abs() > 2.0 =>
+        {
+            Err("ball position outside the field")
+        }
+        _ => Ok(()),
+    }
+}
+
+fn flood_message() -> String {
+    format!(
+        "too many packets kept arriving from the other player's address for {} seconds, so \
+        the game was ended. someone might be flooding you (or them).",
+        ratelimit::ABUSE_DURATION.as_secs()
+    )
+}
+
+fn print_permission_help() {
+    eprintln!("icmpong needs a raw ICMP socket, which requires root or CAP_NET_RAW.");
+    eprintln!(
+        "unprivileged ICMP sockets (allowed by the net.ipv4.ping_group_range sysctl) can't \
+        be used instead, since the kernel only hands them echo replies to their own \
+        requests and never the echo requests sent by the other player."
+    );
+    let executable = std::env::current_exe()
+        .map(|path| path.display().to_string())
+        .unwrap_or("icmpong".to_string());
+    eprintln!(
+        "to play without sudo, grant the capability once: sudo setcap cap_net_raw+ep {executable}"
+    );
+}
+
+/// Prints a line of connection progress over the "waiting for peer" status.
+fn print_status(message: &str) {
+    let _ = stdout()
+        .execute(cursor::MoveToColumn(0))
+        .and_then(|stdout| stdout.execute(terminal::Clear(terminal::ClearType::CurrentLine)));
+    if !message.is_empty() {
+        println!("{message}");
+    }
+}
+
+/// When to send Pings while waiting for the other player, and when to give up.
+struct PingSchedule {
+    deadline: Instant,
+    next_ping: Instant,
+    interval: Duration,
+}
+
+impl PingSchedule {
+    fn new(start: Instant, timeout: Duration) -> Self {
+        Self {
+            deadline: start + timeout,
+            next_ping: start,
+            interval: PING_INTERVAL,
+        }
+    }
+
+    /// Whether a Ping is due at `now`. Each one doubles the wait for the next.
+    fn ping_due(&mut self, now: Instant) -> bool {
+        if now < self.next_ping {
+            return false;
+        }
+        self.next_ping = now + self.interval;
+        self.interval = (self.interval * 2).min(MAXIMUM_PING_INTERVAL);
+        true
+    }
+
+    fn timed_out(&self, now: Instant) -> bool {
+        now >= self.deadline
+    }
+}
+
 fn cleanup() -> anyhow::Result<()> {
     terminal::disable_raw_mode()?;
     stdout().execute(cursor::Show)?.flush()?;
@@ -487,173 +779,310 @@ fn cleanup() -> anyhow::Result<()> {
 }
 
 fn connection_loop(
-    name: Option<String>,
     connection: Arc<Mutex<IcmPongConnection>>,
-    rx: &mut TransportReceiver,
-    stop_game: Arc<Mutex<bool>>,
-    connection_established: Arc<Mutex<bool>>,
-    peer_client_id: Arc<Mutex<Option<u32>>>,
-    peer_start_game: Arc<Mutex<bool>>,
-    ball: Arc<Mutex<GameObject>>,
-    peer_player: Arc<Mutex<Option<Arc<Mutex<GameObject>>>>>,
-    peer_name: Arc<Mutex<Option<String>>>,
-    score: Arc<Mutex<[u32; 2]>>,
+    transport: Arc<dyn Transport>,
+    events: Sender<NetEvent>,
+    shutdown: Arc<AtomicBool>,
 ) {
-    let mut client_id = None;
-    loop {
-        let packet = match icmpv6_packet_iter(rx).next() {
-            Ok(packet) => {
-                if packet.1 != connection.lock().unwrap().peer {
-                    continue;
-                } else {
-                    packet.0.payload().to_owned()
-                }
-            }
-            Err(error) => {
-                let _ = cleanup();
-                eprintln!("unable to iterate packets: {error}");
-                return;
-            }
+    let fail = |message: String| {
+        let _ = events.send(NetEvent::Failed(message));
+    };
+    let mut reported = Reported::default();
+    while !shutdown.load(Ordering::Relaxed) {
+        let received = match transport.receive_timeout(RECEIVE_TIMEOUT) {
+            Ok(received) => received,
+            Err(error) => return fail(format!("unable to iterate packets: {error}")),
         };
-        if &packet[0..7] == "ICMPong".as_bytes() {
-            let packet_version = packet[7];
-            if packet_version != icmpong::PROTOCOL_VERSION {
-                let _ = cleanup();
-                eprintln!("the other player is on a different version of ICMPong!");
-                eprintln!(
-                    "you are v{}, they are v{packet_version}. please update to the same version.",
-                    icmpong::PROTOCOL_VERSION,
-                );
-                return;
-            }
-            if packet.len() != 45 {
-                let _ = cleanup();
-                eprintln!(
-                    "invalid packet size received: expected 45, found {}",
-                    packet.len()
-                );
-                return;
+        let mut connection = connection.lock().unwrap();
+        if let Some((request, source)) = received {
+            if let Err(message) = receive(&mut connection, &request, &source, &events) {
+                return fail(message);
             }
-            let packet_client_id = u32::from_ne_bytes(match packet[8..12].try_into() {
-                Ok(packet_client_id) => packet_client_id,
-                Err(error) => {
-                    let _ = cleanup();
-                    eprintln!("unable to deserialize peer client id: {error}");
-                    return;
-                }
-            });
-            if packet_client_id == connection.lock().unwrap().client_id {
-                continue;
+        }
+        if let Err(error) = connection.handle(SessionEvent::Tick) {
+            return fail(format!("unable to send packet: {error:?}"));
+        }
+        reported.update(&connection, &events);
+        if connection.session.state() == SessionState::Closing
+            && !connection.session.has_unacknowledged()
+        {
+            return;
+        }
+    }
+}
+
+/// What the game loop has been told about the session, so that it's only told
+/// about changes.
+#[derive(Default)]
+struct Reported {
+    state: Option<SessionState>,
+    /// Whole seconds left until the session times out.
+    connection_lost: Option<u64>,
+    rejected_packets: u64,
+    rejected_at: Option<Instant>,
+}
+
+impl Reported {
+    fn update(&mut self, connection: &IcmPongConnection, events: &Sender<NetEvent>) {
+        let now = Instant::now();
+        let state = connection.session.state();
+        if self.state != Some(state) {
+            self.state = Some(state);
+            let _ = events.send(NetEvent::State(state));
+        }
+        let remaining = connection.session.connection_lost(now);
+        let connection_lost = remaining.map(|remaining| remaining.as_secs_f32().ceil() as u64);
+        if self.connection_lost != connection_lost {
+            self.connection_lost = connection_lost;
+            let _ = events.send(NetEvent::ConnectionLost(remaining));
+        }
+        let due = self.rejected_at.map_or(true, |rejected_at| {
+            now.saturating_duration_since(rejected_at) >= REJECTION_REPORT_INTERVAL
+        });
+        match connection.rejection {
+            Some(ref reason) if due && connection.rejected_packets != self.rejected_packets => {
+                let _ = events.send(NetEvent::Rejected {
+                    count: connection.rejected_packets - self.rejected_packets,
+                    reason: reason.clone(),
+                });
+                self.rejected_packets = connection.rejected_packets;
+                self.rejected_at = Some(now);
             }
-            let packet_type: IcmPongPacketType =
-                match num_traits::FromPrimitive::from_u8(packet[12]) {
-                    Some(packet_type) => packet_type,
-                    None => {
-                        let _ = cleanup();
-                        eprintln!("unknown packet type received ({})", packet[12]);
-                        return;
-                    }
-                };
-            let packet_data = &packet[13..45];
-
-            if packet_type == IcmPongPacketType::Disconnect {
-                let _ = connection
-                    .lock()
-                    .unwrap()
-                    .send_packet(IcmPongPacket::new(IcmPongPacketType::Disconnect, &[0; 32]));
-                let _ = cleanup();
-                *stop_game.lock().unwrap() = true;
-                return;
-            } else if packet_type == IcmPongPacketType::Ping {
-                println!("received Ping packet from peer! sending Ready packet...");
-                let data = &mut [0u8; 32];
-                if let Some(ref name) = name {
-                    data[0] = name.len() as u8;
-                    data[1..name.len() + 1].copy_from_slice(name.clone().as_bytes());
-                }
-                match connection
-                    .lock()
-                    .unwrap()
-                    .send_packet(IcmPongPacket::new(IcmPongPacketType::Ready, &data))
-                {
-                    Ok(_) => (),
-                    Err(error) => {
-                        let _ = cleanup();
-                        eprintln!("unable to send Ready packet: {error:?}");
-                        return;
-                    }
-                };
-            } else if packet_type == IcmPongPacketType::Ready && client_id.is_none() {
-                println!("received Ready packet from peer! echoing...");
-                if packet_data[0] != 0 {
-                    let name_length = packet_data[0] as usize;
-                    *peer_name.lock().unwrap() = Some(
-                        std::str::from_utf8(&packet_data[1..name_length + 1])
-                            .unwrap()
-                            .to_string(),
-                    )
-                }
+            _ => (),
+        }
+    }
+}
 
-                let data = &mut [0u8; 32];
-                if let Some(ref name) = name {
-                    data[0] = name.len() as u8;
-                    data[1..name.len() + 1].copy_from_slice(name.clone().as_bytes());
-                }
-                match connection
-                    .lock()
-                    .unwrap()
-                    .send_packet(IcmPongPacket::new(IcmPongPacketType::Ready, &data))
-                {
-                    Ok(_) => (),
-                    Err(error) => {
-                        let _ = cleanup();
-                        eprintln!("unable to send Ready packet: {error:?}");
-                        return;
-                    }
-                };
-                client_id = Some(packet_client_id);
-                *peer_client_id.lock().unwrap() = client_id;
-                println!(
-                    "starting game with {} (peer client id: {packet_client_id})...",
-                    connection.lock().unwrap().peer
-                );
-                *connection_established.lock().unwrap() = true;
+/// Explains an error from the session, `version` being the one we speak.
+fn error_message(error: &IcmPongError, version: u8) -> String {
+    match error {
+        IcmPongError::IncompatibleVersion(handshake) => format!(
+            "the other player is on an incompatible version of ICMPong!\n\
+            you support v{}-v{}, they support v{}-v{}. please update to the same version.",
+            icmpong::MINIMUM_PROTOCOL_VERSION,
+            icmpong::PROTOCOL_VERSION,
+            handshake.minimum_version,
+            handshake.maximum_version,
+        ),
+        IcmPongError::VersionMismatch(packet_version) => format!(
+            "the other player is on a different version of ICMPong!\n\
+            you are v{version}, they are v{packet_version}. please update to the same version.",
+        ),
+        IcmPongError::EncryptionMismatch(true) => {
+            "the other player is encrypting! please use --encrypt too.".to_string()
+        }
+        IcmPongError::EncryptionMismatch(false) => {
+            "the other player isn't encrypting! they have to use --encrypt too.".to_string()
+        }
+        error => format!("unable to send packet: {error:?}"),
+    }
+}
+
+/// Handles a packet from `source`. Returns why the connection can't go on,
+/// if it can't.
+fn receive(
+    connection: &mut IcmPongConnection,
+    request: &EchoRequest,
+    source: &PeerAddress,
+    events: &Sender<NetEvent>,
+) -> Result<(), String> {
+    if !connection.is_peer(source) {
+        return Ok(());
+    }
+    if !connection.rate_limiter.admit(Instant::now()) {
+        connection.reject("too many packets");
+        if connection.rate_limiter.is_abused() {
+            let _ = connection.handle(SessionEvent::Quit);
+            return Err(flood_message());
+        }
+        return Ok(());
+    }
+    let packet = match connection.decode(request) {
+        Ok(packet) => packet,
+        Err(DecodeError::NotIcmPong | DecodeError::IdentifierMismatch(_)) => return Ok(()),
+        // only the handshake tells us how the peer is set up, anyone else
+        // can send these
+        Err(
+            error @ (DecodeError::SecretRequired
+            | DecodeError::EncryptionRequired
+            | DecodeError::VersionMismatch(_)),
+        ) if connection.session.is_established() => {
+            connection.reject(error);
+            return Ok(());
+        }
+        Err(DecodeError::SecretRequired) => {
+            return Err(
+                "the other player is using a secret! please use the same one with --secret."
+                    .to_string(),
+            );
+        }
+        Err(DecodeError::EncryptionRequired) => {
+            return Err("the other player is encrypting! please use --encrypt too.".to_string());
+        }
+        Err(DecodeError::VersionMismatch(packet_version)) => {
+            return Err(format!(
+                "the other player is on a different version of ICMPong!\n\
+                you are v{}, they are v{packet_version}. please update to the same version.",
+                connection.session.version(),
+            ));
+        }
+        Err(error) => {
+            connection.reject(error);
+            return Ok(());
+        }
+    };
+
+    let packet_type = packet.packet.packet_type();
+    if !connection
+        .rate_limiter
+        .admit_type(packet_type, Instant::now())
+    {
+        connection.reject(format!("too many {packet_type:?} packets"));
+        if connection.rate_limiter.is_abused() {
+            let _ = connection.handle(SessionEvent::Quit);
+            return Err(flood_message());
+        }
+        return Ok(());
+    }
+    let was_established = connection.session.is_established();
+    let own_packet = packet.client_id == connection.session.client_id();
+    if !own_packet && !was_established {
+        let status = match packet.packet {
+            IcmPongPacket::Ping(ping) if ping.game == connection.session.game => {
+                "received Ping packet from peer! sending Ready packet..."
             }
+            IcmPongPacket::Ready(_) => "received Ready packet from peer! echoing...",
+            _ => "",
+        };
+        if !status.is_empty() {
+            let _ = events.send(NetEvent::Status(status.to_string()));
+        }
+    }
+    let update = match connection.handle(SessionEvent::Received(packet)) {
+        Ok(update) => update,
+        Err(error) => return Err(error_message(&error, connection.session.version())),
+    };
+    if !was_established && connection.session.is_established() {
+        // only the address the peer's Ready came from, anyone could Ping us
+        connection.settle_peer(source);
+        let _ = events.send(NetEvent::Status(format!(
+            "starting game with {} (peer client id: {})...",
+            connection.peer,
+            connection.session.peer().unwrap().client_id,
+        )));
+    }
 
-            if client_id.is_some() {
-                if packet_type == IcmPongPacketType::Start {
-                    *peer_start_game.lock().unwrap() = true;
-                } else if packet_type == IcmPongPacketType::PaddlePosition {
-                    let player = peer_player.lock().unwrap();
-                    match player.to_owned() {
-                        Some(player) => {
-                            player.lock().unwrap().x_position =
-                                u16::from_ne_bytes(packet_data[0..2].try_into().unwrap());
-                            player.lock().unwrap().y_position =
-                                u16::from_ne_bytes(packet_data[2..4].try_into().unwrap());
-                        }
-                        None => (),
-                    }
-                } else if packet_type == IcmPongPacketType::BallUpdate {
-                    ball.lock().unwrap().x_position =
-                        u16::from_ne_bytes(packet_data[0..2].try_into().unwrap());
-                    ball.lock().unwrap().y_position =
-                        u16::from_ne_bytes(packet_data[2..4].try_into().unwrap());
-                    ball.lock().unwrap().x_movement =
-                        f32::from_ne_bytes(packet_data[4..8].try_into().unwrap());
-                    ball.lock().unwrap().y_movement =
-                        f32::from_ne_bytes(packet_data[8..12].try_into().unwrap());
-                    ball.lock().unwrap().xf32 =
-                        f32::from_ne_bytes(packet_data[12..16].try_into().unwrap());
-                    ball.lock().unwrap().yf32 =
-                        f32::from_ne_bytes(packet_data[16..20].try_into().unwrap());
-                } else if packet_type == IcmPongPacketType::ScoreUpdate {
-                    score.lock().unwrap()[0] =
-                        u32::from_ne_bytes(packet_data[0..4].try_into().unwrap());
-                    score.lock().unwrap()[1] =
-                        u32::from_ne_bytes(packet_data[4..8].try_into().unwrap());
-                }
+    let event = match update {
+        Some(IcmPongPacket::PaddlePosition(paddle)) => NetEvent::PaddlePosition(paddle),
+        Some(IcmPongPacket::BallUpdate(ball)) => NetEvent::BallUpdate(ball),
+        Some(IcmPongPacket::ScoreUpdate(score)) => NetEvent::ScoreUpdate(score),
+        _ => return Ok(()),
+    };
+    let _ = events.send(event);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use icmpong::crypto::Encryption;
+    use icmpong::transport::LoopbackTransport;
+    use icmpong::OwnedPacket;
+
+    #[test]
+    fn pings_back_off_until_the_connect_timeout() {
+        let start = Instant::now();
+        let mut schedule = PingSchedule::new(start, Duration::from_secs(30));
+        let mut pings = Vec::new();
+        let mut now = start;
+        while !schedule.timed_out(now) {
+            if schedule.ping_due(now) {
+                pings.push((now - start).as_millis());
             }
+            now += Duration::from_millis(50);
         }
+        assert_eq!(now - start, Duration::from_secs(30));
+        // the wait doubles from 250ms up to 4s
+        assert_eq!(
+            pings,
+            [0, 250, 750, 1750, 3750, 7750, 11750, 15750, 19750, 23750, 27750]
+        );
+    }
+
+    /// A connection that has finished the handshake with `other`, whose
+    /// packets come from `source`.
+    fn established() -> (IcmPongConnection, IcmPongConnection, PeerAddress) {
+        let address: PeerAddress = "2001:db8::1".parse().unwrap();
+        let source: PeerAddress = "2001:db8::2".parse().unwrap();
+        let (transport, other_transport) = LoopbackTransport::pair(address, source);
+        let mut connection = IcmPongConnection::new(source, Arc::new(transport), None);
+        let mut other = IcmPongConnection::new(address, Arc::new(other_transport), None);
+        connection.handle(SessionEvent::Connect).unwrap();
+        let (ping, _) = other.transport.receive_from().unwrap();
+        let ping = other.decode(&ping).unwrap();
+        other.handle(SessionEvent::Received(ping)).unwrap();
+        let (ready, _) = connection.transport.receive_from().unwrap();
+        let ready = connection.decode(&ready).unwrap();
+        connection.handle(SessionEvent::Received(ready)).unwrap();
+        assert!(connection.session.is_established());
+        (connection, other, source)
+    }
+
+    #[test]
+    fn established_sessions_survive_mismatched_packets() {
+        let (mut connection, _other, source) = established();
+        let start = OwnedPacket {
+            session_id: connection.session.session_id(),
+            ..IcmPongPacket::decode(&IcmPongPacket::Start.encode(icmpong::PROTOCOL_VERSION, 1))
+                .unwrap()
+        };
+        let mut newer = start.to_echo_request(1, None);
+        newer.payload[7] = icmpong::PROTOCOL_VERSION + 1;
+        let signed = start.to_echo_request(1, Some(&SecretKey::derive("secret")));
+        let mut sealed = start.to_echo_request(1, None);
+        sealed.payload = Encryption::new().seal(&[1; 32], 1, &sealed.payload);
+
+        let (events, _) = channel();
+        for (count, request) in [newer, signed, sealed].iter().enumerate() {
+            assert_eq!(receive(&mut connection, request, &source, &events), Ok(()));
+            assert_eq!(connection.rejected_packets, count as u64 + 1);
+            assert!(connection.session.is_established());
+        }
+
+        // before the session is established, they end the connection
+        let (transport, _) = LoopbackTransport::pair(source, source);
+        let mut connecting = IcmPongConnection::new(source, Arc::new(transport), None);
+        let mut newer = start.to_echo_request(1, None);
+        newer.payload[7] = icmpong::PROTOCOL_VERSION + 1;
+        assert!(receive(&mut connecting, &newer, &source, &events).is_err());
+    }
+
+    #[test]
+    fn the_address_that_establishes_the_session_becomes_the_peer() {
+        let address: PeerAddress = "2001:db8::1".parse().unwrap();
+        let source: PeerAddress = "2001:db8::2".parse().unwrap();
+        let stranger: PeerAddress = "2001:db8::3".parse().unwrap();
+        let (transport, other_transport) = LoopbackTransport::pair(address, source);
+        let mut connection = IcmPongConnection::new(source, Arc::new(transport), None);
+        let mut other = IcmPongConnection::new(address, Arc::new(other_transport), None);
+        connection.candidates = vec![source, stranger];
+        connection.handle(SessionEvent::Connect).unwrap();
+
+        // a Ping can come from anywhere
+        let (events, _) = channel();
+        other.handle(SessionEvent::Connect).unwrap();
+        let (ping, _) = connection.transport.receive_from().unwrap();
+        receive(&mut connection, &ping, &stranger, &events).unwrap();
+        assert_eq!(connection.peer, source);
+        assert_eq!(connection.candidates, [source, stranger]);
+
+        let (ping, _) = other.transport.receive_from().unwrap();
+        let ping = other.decode(&ping).unwrap();
+        other.handle(SessionEvent::Received(ping)).unwrap();
+        let (ready, _) = connection.transport.receive_from().unwrap();
+        receive(&mut connection, &ready, &source, &events).unwrap();
+        assert!(connection.session.is_established());
+        assert_eq!(connection.peer, source);
+        assert!(connection.candidates.is_empty());
     }
 }
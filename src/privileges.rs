@@ -0,0 +1,70 @@
+use std::ffi::CString;
+use std::io::{Error, ErrorKind};
+
+/// Gives up root and every capability. Meant to be called once the ICMP socket
+/// is open, so that the rest of the session (including the parsing of packets
+/// from the peer) runs as the invoking user.
+pub fn drop_privileges() -> std::io::Result<()> {
+    if unsafe { libc::geteuid() } == 0 {
+        let (uid, gid) = invoking_user()?;
+        // the group has to be changed first, as we can't change it after
+        // giving up root
+        if unsafe { libc::setgroups(0, std::ptr::null()) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        if unsafe { libc::setgid(gid) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        if unsafe { libc::setuid(uid) } != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    for capability_set in [
+        caps::CapSet::Effective,
+        caps::CapSet::Permitted,
+        caps::CapSet::Inheritable,
+        caps::CapSet::Ambient,
+    ] {
+        caps::clear(None, capability_set)
+            .map_err(|error| Error::new(ErrorKind::Other, error.to_string()))?;
+    }
+
+    if unsafe { libc::setuid(0) } == 0 {
+        return Err(Error::new(ErrorKind::Other, "still able to regain root"));
+    }
+    Ok(())
+}
+
+/// The user who ran a setuid root icmpong, the one who ran `sudo icmpong`, or
+/// `nobody` if we were started as root directly.
+fn invoking_user() -> std::io::Result<(libc::uid_t, libc::gid_t)> {
+    // whoever runs a setuid binary controls its environment, so SUDO_UID is
+    // only believed when sudo made root the real user too
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    if uid != 0 {
+        return Ok((uid, gid));
+    }
+    if let (Ok(uid), Ok(gid)) = (std::env::var("SUDO_UID"), std::env::var("SUDO_GID")) {
+        let uid = uid
+            .parse()
+            .map_err(|_| Error::new(ErrorKind::Other, format!("invalid SUDO_UID: {uid}")))?;
+        let gid = gid
+            .parse()
+            .map_err(|_| Error::new(ErrorKind::Other, format!("invalid SUDO_GID: {gid}")))?;
+        if uid != 0 {
+            return Ok((uid, gid));
+        }
+    }
+
+    let name = CString::new("nobody").unwrap();
+    let nobody = unsafe { libc::getpwnam(name.as_ptr()) };
+    if nobody.is_null() {
+        return Err(Error::new(
+            ErrorKind::Other,
+            "not started through sudo, and there is no nobody user to switch to",
+        ));
+    }
+    Ok(unsafe { ((*nobody).pw_uid, (*nobody).pw_gid) })
+}
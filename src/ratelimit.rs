@@ -0,0 +1,197 @@
+use crate::IcmPongPacketType;
+use std::time::{Duration, Instant};
+
+/// How many packets per second (and in a burst) may arrive from the peer's
+/// address, before they're even decoded.
+const TOTAL_LIMIT: (f64, f64) = (400.0, 400.0);
+/// How long packets have to keep being dropped for, to count as abuse.
+pub const ABUSE_DURATION: Duration = Duration::from_secs(5);
+/// How long a break in the drops has to be, for the abuse to count as over.
+const ABUSE_BREAK: Duration = Duration::from_secs(1);
+
+/// How many packets of a type may arrive per second, and in a burst. These
+/// are far above what a client sends (when playing with itself over loopback,
+/// which receives both sides' packets).
+fn limit(packet_type: IcmPongPacketType) -> (f64, f64) {
+    match packet_type {
+        IcmPongPacketType::Ping
+        | IcmPongPacketType::Ready
+        | IcmPongPacketType::Heartbeat
+        | IcmPongPacketType::Disconnect => (10.0, 20.0),
+        IcmPongPacketType::Start | IcmPongPacketType::ScoreUpdate => (20.0, 40.0),
+        IcmPongPacketType::PaddlePosition
+        | IcmPongPacketType::BallUpdate
+        | IcmPongPacketType::Ack => (100.0, 100.0),
+    }
+}
+
+/// Allows `rate` packets per second on average, and up to `burst` at once.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled_at: Option<Instant>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            refilled_at: None,
+        }
+    }
+
+    /// Takes a token for a packet that arrived at `now`, if there's one left.
+    pub fn take(&mut self, now: Instant) -> bool {
+        if let Some(refilled_at) = self.refilled_at {
+            let elapsed = now.saturating_duration_since(refilled_at).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        }
+        self.refilled_at = Some(now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Limits how fast packets from the peer's address are handled, overall and
+/// for each packet type, so that a flood of them (spoofed or not) can't keep
+/// the game from running.
+#[derive(Debug)]
+pub struct RateLimiter {
+    total: TokenBucket,
+    /// Indexed by packet type.
+    per_type: Vec<TokenBucket>,
+    /// When packets started being dropped, without a break of
+    /// [`ABUSE_BREAK`].
+    dropping_since: Option<Instant>,
+    last_dropped: Option<Instant>,
+    abused: bool,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self {
+            total: TokenBucket::new(TOTAL_LIMIT.0, TOTAL_LIMIT.1),
+            per_type: (0..)
+                .map_while(num_traits::FromPrimitive::from_u8)
+                .map(|packet_type| {
+                    let (rate, burst) = limit(packet_type);
+                    TokenBucket::new(rate, burst)
+                })
+                .collect(),
+            dropping_since: None,
+            last_dropped: None,
+            abused: false,
+        }
+    }
+
+    /// Whether a packet that arrived at `now` from the peer's address should
+    /// be decoded at all.
+    pub fn admit(&mut self, now: Instant) -> bool {
+        let admitted = self.total.take(now);
+        self.record(admitted, now)
+    }
+
+    /// Whether a decoded packet of `packet_type` should be handled.
+    pub fn admit_type(&mut self, packet_type: IcmPongPacketType, now: Instant) -> bool {
+        let admitted = self.per_type[packet_type as usize].take(now);
+        self.record(admitted, now)
+    }
+
+    fn record(&mut self, admitted: bool, now: Instant) -> bool {
+        if admitted {
+            return true;
+        }
+        let dropping_since = match (self.dropping_since, self.last_dropped) {
+            (Some(since), Some(last)) if now.saturating_duration_since(last) < ABUSE_BREAK => since,
+            _ => now,
+        };
+        self.dropping_since = Some(dropping_since);
+        self.last_dropped = Some(now);
+        if now.saturating_duration_since(dropping_since) >= ABUSE_DURATION {
+            self.abused = true;
+        }
+        false
+    }
+
+    /// Whether packets kept having to be dropped for [`ABUSE_DURATION`], at
+    /// which point the session should be ended.
+    pub fn is_abused(&self) -> bool {
+        self.abused
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 2.0);
+        assert!(bucket.take(start));
+        assert!(bucket.take(start));
+        assert!(!bucket.take(start));
+        assert!(!bucket.take(start + Duration::from_millis(50)));
+        assert!(bucket.take(start + Duration::from_millis(150)));
+        // never more than the burst
+        let later = start + Duration::from_secs(10);
+        assert!(bucket.take(later));
+        assert!(bucket.take(later));
+        assert!(!bucket.take(later));
+    }
+
+    #[test]
+    fn packet_types_are_limited_separately() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new();
+        let (_, burst) = limit(IcmPongPacketType::Heartbeat);
+        for _ in 0..burst as usize {
+            assert!(limiter.admit_type(IcmPongPacketType::Heartbeat, now));
+        }
+        assert!(!limiter.admit_type(IcmPongPacketType::Heartbeat, now));
+        assert!(limiter.admit_type(IcmPongPacketType::PaddlePosition, now));
+        assert!(!limiter.is_abused());
+    }
+
+    #[test]
+    fn sustained_floods_are_abuse() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new();
+        let mut now = start;
+        let mut first_dropped = None;
+        // twice the limit
+        while !limiter.is_abused() {
+            for _ in 0..TOTAL_LIMIT.0 as usize / 50 {
+                if !limiter.admit(now) {
+                    first_dropped.get_or_insert(now);
+                }
+            }
+            now += Duration::from_millis(10);
+            assert!(now < start + ABUSE_DURATION * 2);
+        }
+        assert!(now - first_dropped.unwrap() >= ABUSE_DURATION);
+
+        // a burst that stops doesn't count
+        let mut limiter = RateLimiter::new();
+        for second in 0..ABUSE_DURATION.as_secs() * 2 {
+            let now = start + Duration::from_secs(second * 2);
+            for _ in 0..TOTAL_LIMIT.1 as usize + 10 {
+                limiter.admit(now);
+            }
+        }
+        assert!(!limiter.is_abused());
+    }
+}
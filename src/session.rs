@@ -0,0 +1,1263 @@
+use crate::{
+    Ack, Handshake, IcmPongError, IcmPongPacket, OwnedPacket, Ping, Ready, FEATURE_ENCRYPTION,
+    MINIMUM_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+/// How often a Heartbeat is sent once the session is established.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+/// How long the peer may be silent before the connection counts as lost.
+pub const LOST_AFTER: Duration = Duration::from_secs(2);
+/// How long the peer may be silent before the session is closed, unless
+/// changed through [`Session::peer_timeout`].
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for an Ack before sending a reliable packet again.
+pub const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(250);
+/// How many of the peer's latest sequence numbers are remembered, to drop
+/// duplicates. Anything older counts as a duplicate too.
+const RECEIVE_WINDOW: u32 = u64::BITS;
+
+/// Where a [`Session`] is in the life of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    /// Nothing has been sent yet.
+    Idle,
+    /// Pinging the peer until it answers with a Ready.
+    PingSent,
+    /// Both sides have sent their Ready, but we haven't pressed start yet.
+    ReadyExchanged,
+    /// We've pressed start and are waiting for the peer to do the same.
+    Lobby,
+    InGame,
+    /// A Disconnect has been sent or received. Nothing is accepted anymore.
+    Closing,
+}
+
+impl SessionState {
+    /// Whether both sides know each other, and haven't disconnected.
+    pub fn is_established(self) -> bool {
+        matches!(self, Self::ReadyExchanged | Self::Lobby | Self::InGame)
+    }
+}
+
+/// Something that happened to a [`Session`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionEvent {
+    /// Time to (re)send a Ping, if the peer hasn't answered yet.
+    Connect,
+    /// A packet arrived from the peer's address.
+    Received(OwnedPacket),
+    /// The local player pressed start.
+    Start,
+    /// The local player quit.
+    Quit,
+    /// Time to send a Heartbeat, retransmit unacknowledged packets and check
+    /// on the peer, if it's due.
+    Tick,
+}
+
+/// What a [`Session`] wants done after handling an event.
+#[derive(Debug, Default, PartialEq)]
+pub struct Transition {
+    /// Packets to send to the peer, in order.
+    pub packets: Vec<OwnedPacket>,
+    /// A PaddlePosition, BallUpdate or ScoreUpdate from the peer, for the game
+    /// to apply.
+    pub update: Option<IcmPongPacket>,
+}
+
+/// A reliable packet that hasn't been acknowledged yet.
+#[derive(Debug)]
+struct Pending {
+    packet: OwnedPacket,
+    sent_at: Instant,
+}
+
+/// The other player, as introduced by its Ready packet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Peer {
+    pub client_id: u32,
+    pub name: Option<String>,
+}
+
+/// The handshake and lobby state machine, without any I/O: events go in and
+/// the packets to send come out.
+///
+/// Packets are handled the same way no matter how often or in which order
+/// they arrive:
+/// - our own packets (echo replies from the kernel) are ignored
+/// - a Ping is always answered with a Ready, so a peer that lost ours can ask
+///   again. That Ready isn't retransmitted, as the Ping will be. Pings from
+///   another game (see [`Session::game`]) are ignored, so that several games
+///   between the same hosts don't pair up the wrong clients
+/// - the first Ready decides who the peer is. Later Readies, and any packet
+///   from another client id, are ignored
+/// - Start, game updates and Disconnect are ignored until there is a peer
+/// - a Start is only remembered once, and everything is ignored once closing
+/// - a PaddlePosition, BallUpdate or ScoreUpdate older than the last one
+///   applied is dropped
+/// - apart from Pings, only packets with our session id are accepted, so
+///   Readies answering another client's Ping and packets from another game
+///   are ignored
+/// - a Ready only counts if it echoes our cookie, and everything after it has
+///   to carry the token made from both cookies, so that a Ready (or anything
+///   else) can't be injected by someone who doesn't see our packets
+/// - only that Ready settles the version and features. Pings, and Readies
+///   that don't echo our cookie, can't end the session: why they couldn't
+///   lead to a game is kept as [`Session::incompatibility`]
+///
+/// Reliable packets (see [`IcmPongPacket::is_reliable`]) are numbered, sent
+/// again every [`RETRANSMIT_INTERVAL`] until the peer acknowledges them, and
+/// acknowledged whenever they arrive. Only the first copy of each is handled.
+///
+/// Once established, anything from the peer (including Heartbeats) keeps the
+/// session alive. If the peer is silent for [`Session::peer_timeout`], the
+/// session closes as if it had disconnected.
+#[derive(Debug)]
+pub struct Session {
+    client_id: u32,
+    name: Option<String>,
+    state: SessionState,
+    peer: Option<Peer>,
+    peer_started: bool,
+    version: u8,
+    features: u32,
+    session_id: u16,
+    /// Our random challenge, which the peer's Ready has to echo.
+    cookie: u32,
+    peer_cookie: u32,
+    /// Made from both cookies once the Readies are exchanged, 0 until then.
+    token: u32,
+    last_heard: Option<Instant>,
+    last_heartbeat: Option<Instant>,
+    timed_out: bool,
+    next_sequence: u32,
+    unacknowledged: Vec<Pending>,
+    received_highest: u32,
+    /// Bit `n` is set if `received_highest - n` has been received.
+    received_window: u64,
+    started_at: Instant,
+    next_state_sequence: u32,
+    /// The sequence numbers of the newest state updates applied, older ones
+    /// arriving late are dropped.
+    last_paddle_sequence: u32,
+    last_ball_sequence: u32,
+    /// The same for ScoreUpdates, which are numbered as reliable packets.
+    last_score_sequence: u32,
+    /// Why the last handshake packet that didn't echo our cookie couldn't
+    /// lead to a game. Anyone can send those, so they don't end the session.
+    incompatibility: Option<IcmPongError>,
+    pub peer_timeout: Duration,
+    /// Whether to ask the peer for encryption, which it has to want too.
+    pub encrypt: bool,
+    /// The [`crate::game_tag`] Pings have to carry to be answered, 0 for
+    /// none.
+    pub game: u32,
+}
+
+impl Session {
+    pub fn new(name: Option<String>) -> Self {
+        Self::with_client_id(rand::thread_rng().gen(), name)
+    }
+
+    pub fn with_client_id(client_id: u32, name: Option<String>) -> Self {
+        Self {
+            client_id,
+            name,
+            state: SessionState::Idle,
+            peer: None,
+            peer_started: false,
+            version: PROTOCOL_VERSION,
+            features: 0,
+            session_id: 0,
+            cookie: rand::thread_rng().gen_range(1..=u32::MAX),
+            peer_cookie: 0,
+            token: 0,
+            last_heard: None,
+            last_heartbeat: None,
+            timed_out: false,
+            next_sequence: 0,
+            unacknowledged: Vec::new(),
+            received_highest: 0,
+            received_window: 0,
+            started_at: Instant::now(),
+            next_state_sequence: 0,
+            last_paddle_sequence: 0,
+            last_ball_sequence: 0,
+            last_score_sequence: 0,
+            incompatibility: None,
+            peer_timeout: PEER_TIMEOUT,
+            encrypt: false,
+            game: 0,
+        }
+    }
+
+    pub fn client_id(&self) -> u32 {
+        self.client_id
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    pub fn peer(&self) -> Option<&Peer> {
+        self.peer.as_ref()
+    }
+
+    /// The cookie the peer's Ready came with, or 0 until then.
+    pub fn peer_cookie(&self) -> u32 {
+        self.peer_cookie
+    }
+
+    /// Whether the peer has pressed start.
+    pub fn peer_started(&self) -> bool {
+        self.peer_started
+    }
+
+    /// The protocol version agreed on with the peer ([`PROTOCOL_VERSION`]
+    /// until then).
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// The optional features both sides support.
+    pub fn features(&self) -> u32 {
+        self.features
+    }
+
+    /// The id of the game with the peer, or 0 until it's settled.
+    pub fn session_id(&self) -> u16 {
+        self.session_id
+    }
+
+    /// Whether both sides know each other, and haven't disconnected.
+    pub fn is_established(&self) -> bool {
+        self.state.is_established()
+    }
+
+    /// Why the last Ping, or Ready that didn't echo our cookie, came from a
+    /// client we can't play with. Worth showing if the peer never answers.
+    pub fn incompatibility(&self) -> Option<&IcmPongError> {
+        self.incompatibility.as_ref()
+    }
+
+    /// Whether the session was closed because the peer stopped answering.
+    pub fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+
+    /// Whether any reliable packets are still waiting for an Ack.
+    pub fn has_unacknowledged(&self) -> bool {
+        !self.unacknowledged.is_empty()
+    }
+
+    /// How long is left until the session times out, if the peer has been
+    /// silent for longer than [`LOST_AFTER`].
+    pub fn connection_lost(&self, now: Instant) -> Option<Duration> {
+        match self.last_heard {
+            Some(last_heard) if self.is_established() => {
+                let silence = now.saturating_duration_since(last_heard);
+                (silence >= LOST_AFTER).then(|| self.peer_timeout.saturating_sub(silence))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn handle(&mut self, event: SessionEvent) -> Result<Transition, IcmPongError> {
+        self.handle_at(event, Instant::now())
+    }
+
+    /// Handles `event` as if it happened at `now`.
+    pub fn handle_at(
+        &mut self,
+        event: SessionEvent,
+        now: Instant,
+    ) -> Result<Transition, IcmPongError> {
+        let mut transition = Transition::default();
+        match event {
+            SessionEvent::Connect => {
+                if let SessionState::Idle | SessionState::PingSent = self.state {
+                    self.state = SessionState::PingSent;
+                    let ping = Ping {
+                        handshake: self.handshake(),
+                        game: self.game,
+                    };
+                    let ping = self.outgoing(IcmPongPacket::Ping(ping), now);
+                    transition.packets.push(ping);
+                }
+            }
+            SessionEvent::Received(packet) => return self.receive(packet, now),
+            SessionEvent::Start => {
+                if let SessionState::ReadyExchanged | SessionState::Lobby = self.state {
+                    self.state = if self.peer_started {
+                        SessionState::InGame
+                    } else {
+                        SessionState::Lobby
+                    };
+                    transition
+                        .packets
+                        .push(self.outgoing(IcmPongPacket::Start, now));
+                }
+            }
+            SessionEvent::Quit => {
+                let established = self.is_established();
+                self.state = SessionState::Closing;
+                self.unacknowledged.clear();
+                if established {
+                    transition
+                        .packets
+                        .push(self.outgoing(IcmPongPacket::Disconnect, now));
+                }
+            }
+            SessionEvent::Tick => {
+                for pending in &mut self.unacknowledged {
+                    if now.saturating_duration_since(pending.sent_at) >= RETRANSMIT_INTERVAL {
+                        pending.sent_at = now;
+                        transition.packets.push(pending.packet.clone());
+                    }
+                }
+                let (Some(last_heard), true) = (self.last_heard, self.is_established()) else {
+                    return Ok(transition);
+                };
+                if now.saturating_duration_since(last_heard) >= self.peer_timeout {
+                    self.state = SessionState::Closing;
+                    self.timed_out = true;
+                    self.unacknowledged.clear();
+                    // in case it comes back, so that it doesn't wait on us
+                    // too. there's no point in waiting for an Ack though
+                    transition.packets = vec![self.unsequenced(IcmPongPacket::Disconnect, now)];
+                    return Ok(transition);
+                }
+                match self.last_heartbeat {
+                    Some(last_heartbeat) if now - last_heartbeat < HEARTBEAT_INTERVAL => (),
+                    _ => {
+                        self.last_heartbeat = Some(now);
+                        transition
+                            .packets
+                            .push(self.outgoing(IcmPongPacket::Heartbeat, now));
+                    }
+                }
+            }
+        }
+        Ok(transition)
+    }
+
+    /// Adds the header to a packet for the peer. Reliable packets are numbered
+    /// and kept until they're acknowledged. Sending one that is still waiting
+    /// for its Ack sends the same packet again, instead of a second one.
+    /// State updates are numbered on their own.
+    pub fn outgoing(&mut self, packet: IcmPongPacket, now: Instant) -> OwnedPacket {
+        let mut packet = self.unsequenced(packet, now);
+        if packet.packet.is_state_update() {
+            self.next_state_sequence = self.next_state_sequence.wrapping_add(1).max(1);
+            packet.sequence = self.next_state_sequence;
+            return packet;
+        }
+        if !packet.packet.is_reliable() {
+            return packet;
+        }
+        if let Some(pending) = self
+            .unacknowledged
+            .iter_mut()
+            .find(|pending| pending.packet.packet == packet.packet)
+        {
+            pending.sent_at = now;
+            return pending.packet.clone();
+        }
+        self.next_sequence = self.next_sequence.wrapping_add(1).max(1);
+        packet.sequence = self.next_sequence;
+        self.unacknowledged.push(Pending {
+            packet: packet.clone(),
+            sent_at: now,
+        });
+        packet
+    }
+
+    fn unsequenced(&self, packet: IcmPongPacket, now: Instant) -> OwnedPacket {
+        let (cookie, token) = match packet {
+            IcmPongPacket::Ping(_) => (self.cookie, 0),
+            IcmPongPacket::Ready(_) => (self.cookie, self.peer_cookie),
+            _ => (0, self.token),
+        };
+        OwnedPacket {
+            version: self.version,
+            client_id: self.client_id,
+            sequence: 0,
+            timestamp: now.saturating_duration_since(self.started_at).as_millis() as u32,
+            session_id: self.session_id,
+            cookie,
+            token,
+            packet,
+        }
+    }
+
+    fn receive(&mut self, packet: OwnedPacket, now: Instant) -> Result<Transition, IcmPongError> {
+        let mut transition = Transition::default();
+        if packet.client_id == self.client_id {
+            return Ok(transition);
+        }
+        // Pings are sent before there's a session (as are handshakes we
+        // can't fully decode), everything else has to be in ours
+        let expected_session_id = match self.peer {
+            Some(_) => self.session_id,
+            None => session_id_for(self.client_id, packet.client_id),
+        };
+        let handshake = matches!(
+            packet.packet,
+            IcmPongPacket::Ping(_) | IcmPongPacket::Ready(_)
+        );
+        if packet.session_id != expected_session_id && !(handshake && packet.session_id == 0) {
+            return Ok(transition);
+        }
+        let established = self.peer.is_some();
+        let token_valid = match packet.packet {
+            IcmPongPacket::Ping(_) => true,
+            IcmPongPacket::Ready(ref ready) if packet.token != self.cookie => {
+                // a version we can't speak might not echo the cookie, but all
+                // such a Ready can do is tell us that we might not be able to
+                // play
+                let unknown_version =
+                    !(MINIMUM_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&packet.version);
+                if unknown_version && !established {
+                    if let Err(error) = self.negotiate(&ready.handshake) {
+                        self.incompatibility = Some(error);
+                    }
+                }
+                false
+            }
+            IcmPongPacket::Ready(_) => true,
+            // the peer doesn't have a token yet when acknowledging our Ready
+            IcmPongPacket::Ack(_) => {
+                packet.token == self.cookie || (established && packet.token == self.token)
+            }
+            _ => established && packet.token == self.token,
+        };
+        if !token_valid {
+            return Ok(transition);
+        }
+        if let Some(ref peer) = self.peer {
+            if packet.client_id != peer.client_id {
+                return Ok(transition);
+            }
+            if packet.version != self.version {
+                return Err(IcmPongError::VersionMismatch(packet.version));
+            }
+            self.last_heard = Some(now);
+        }
+
+        if let IcmPongPacket::Ack(ack) = packet.packet {
+            self.unacknowledged
+                .retain(|pending| pending.packet.sequence != ack.sequence);
+            return Ok(transition);
+        }
+        if packet.packet.is_reliable() && packet.sequence != 0 {
+            // duplicates are acknowledged again too, as our first Ack might
+            // have been lost
+            let ack = IcmPongPacket::Ack(Ack {
+                sequence: packet.sequence,
+            });
+            let mut ack = self.unsequenced(ack, now);
+            if let IcmPongPacket::Ready(_) = packet.packet {
+                ack.token = packet.cookie;
+            }
+            transition.packets.push(ack);
+            if !self.first_receipt(packet.sequence) {
+                return Ok(transition);
+            }
+        }
+        if self.state == SessionState::Closing {
+            return Ok(transition);
+        }
+
+        match packet.packet {
+            // from a client in another game between the same hosts
+            IcmPongPacket::Ping(ping) if ping.game != self.game => (),
+            IcmPongPacket::Ping(ping) => {
+                // anyone can send a Ping, so it can't end the session or
+                // decide the version, but it's answered in one it can read.
+                // our Ready tells an incompatible client that we can't play
+                let mut version = self.version;
+                if self.peer.is_none() {
+                    match self.negotiate(&ping.handshake) {
+                        Ok((negotiated, _)) => version = negotiated,
+                        Err(error) => self.incompatibility = Some(error),
+                    }
+                }
+                // unnumbered, so that it isn't retransmitted: the Ping will
+                // be, if the answer gets lost
+                let ready = OwnedPacket {
+                    version,
+                    session_id: expected_session_id,
+                    token: packet.cookie,
+                    ..self.unsequenced(self.ready(), now)
+                };
+                transition.packets.push(ready);
+            }
+            IcmPongPacket::Ready(ready) if self.peer.is_none() => {
+                // it echoes our cookie, so it's from the client we pinged
+                (self.version, self.features) = self.negotiate(&ready.handshake)?;
+                self.peer = Some(Peer {
+                    client_id: packet.client_id,
+                    name: ready.name,
+                });
+                self.session_id = expected_session_id;
+                self.peer_cookie = packet.cookie;
+                self.token = self.cookie ^ packet.cookie;
+                self.state = SessionState::ReadyExchanged;
+                self.last_heard = Some(now);
+                let ready = self.outgoing(self.ready(), now);
+                transition.packets.push(ready);
+            }
+            IcmPongPacket::Ready(_) => (),
+            _ if self.peer.is_none() => (),
+            IcmPongPacket::Start => {
+                self.peer_started = true;
+                if self.state == SessionState::Lobby {
+                    self.state = SessionState::InGame;
+                }
+            }
+            IcmPongPacket::Disconnect => {
+                // the Ack is all the answer it needs
+                self.state = SessionState::Closing;
+                self.unacknowledged.clear();
+            }
+            IcmPongPacket::Heartbeat | IcmPongPacket::Ack(_) => (),
+            IcmPongPacket::PaddlePosition(_)
+            | IcmPongPacket::BallUpdate(_)
+            | IcmPongPacket::ScoreUpdate(_) => {
+                let last_sequence = match packet.packet {
+                    IcmPongPacket::BallUpdate(_) => &mut self.last_ball_sequence,
+                    IcmPongPacket::ScoreUpdate(_) => &mut self.last_score_sequence,
+                    _ => &mut self.last_paddle_sequence,
+                };
+                // an older update that arrived late would undo a newer one
+                if packet.sequence > *last_sequence {
+                    *last_sequence = packet.sequence;
+                    transition.update = Some(packet.packet);
+                }
+            }
+        }
+        Ok(transition)
+    }
+
+    /// Remembers that the peer's reliable packet `sequence` has arrived.
+    /// Returns false if it already had, or is too old to tell.
+    fn first_receipt(&mut self, sequence: u32) -> bool {
+        if sequence > self.received_highest {
+            let shift = sequence - self.received_highest;
+            self.received_window = self.received_window.checked_shl(shift).unwrap_or(0) | 1;
+            self.received_highest = sequence;
+            return true;
+        }
+        let age = self.received_highest - sequence;
+        if age >= RECEIVE_WINDOW {
+            return false;
+        }
+        let first = self.received_window & 1 << age == 0;
+        self.received_window |= 1 << age;
+        first
+    }
+
+    /// The protocol version and features to use with a client that sent
+    /// `peer`.
+    fn negotiate(&self, peer: &Handshake) -> Result<(u8, u32), IcmPongError> {
+        let peer_encrypts = peer.features & FEATURE_ENCRYPTION != 0;
+        match self.handshake().negotiate(peer) {
+            Some(_) if peer_encrypts != self.encrypt => {
+                Err(IcmPongError::EncryptionMismatch(peer_encrypts))
+            }
+            Some(negotiated) => Ok(negotiated),
+            None => Err(IcmPongError::IncompatibleVersion(*peer)),
+        }
+    }
+
+    fn handshake(&self) -> Handshake {
+        let mut handshake = Handshake::local();
+        if self.encrypt {
+            handshake.features |= FEATURE_ENCRYPTION;
+        }
+        handshake
+    }
+
+    fn ready(&self) -> IcmPongPacket {
+        IcmPongPacket::Ready(Ready {
+            handshake: self.handshake(),
+            name: self.name.clone(),
+        })
+    }
+}
+
+/// The id of the session between two clients, which both of them derive on
+/// their own. Never 0, which stands for no session.
+fn session_id_for(client_id: u32, peer_client_id: u32) -> u16 {
+    let pair = (client_id.min(peer_client_id) as u64) << 32 | client_id.max(peer_client_id) as u64;
+    let folded = pair ^ pair >> 16 ^ pair >> 32 ^ pair >> 48;
+    (folded as u16).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BallUpdate, PaddlePosition, ScoreUpdate};
+
+    const PEER: u32 = 2;
+    const COOKIE: u32 = 0x1234;
+    const PEER_COOKIE: u32 = 0x5678;
+
+    /// Our side, with a known cookie.
+    fn ours(name: Option<String>) -> Session {
+        Session {
+            cookie: COOKIE,
+            ..Session::with_client_id(1, name)
+        }
+    }
+
+    fn from(client_id: u32, packet: IcmPongPacket) -> SessionEvent {
+        sequenced(client_id, 0, packet)
+    }
+
+    fn sequenced(client_id: u32, sequence: u32, packet: IcmPongPacket) -> SessionEvent {
+        SessionEvent::Received(OwnedPacket {
+            version: PROTOCOL_VERSION,
+            client_id,
+            sequence,
+            timestamp: 0,
+            session_id: session_id_for(1, client_id),
+            cookie: PEER_COOKIE,
+            token: match packet {
+                IcmPongPacket::Ping(_) => 0,
+                IcmPongPacket::Ready(_) | IcmPongPacket::Ack(_) => COOKIE,
+                _ => COOKIE ^ PEER_COOKIE,
+            },
+            packet,
+        })
+    }
+
+    fn ack(sequence: u32) -> IcmPongPacket {
+        IcmPongPacket::Ack(Ack { sequence })
+    }
+
+    fn ping(handshake: Handshake) -> IcmPongPacket {
+        IcmPongPacket::Ping(Ping::new(handshake))
+    }
+
+    fn ready(name: &str) -> IcmPongPacket {
+        IcmPongPacket::Ready(Ready {
+            handshake: Handshake::local(),
+            name: Some(name.to_string()),
+        })
+    }
+
+    /// What `ours(None)` answers a Ping with.
+    fn anonymous_ready() -> IcmPongPacket {
+        IcmPongPacket::Ready(Ready {
+            handshake: Handshake::local(),
+            name: None,
+        })
+    }
+
+    fn established() -> Session {
+        let mut session = ours(None);
+        session.handle(SessionEvent::Connect).unwrap();
+        session.handle(from(PEER, ready("peer"))).unwrap();
+        // our own Ready
+        session.handle(from(PEER, ack(1))).unwrap();
+        session
+    }
+
+    fn contents(packets: Vec<OwnedPacket>) -> Vec<IcmPongPacket> {
+        packets.into_iter().map(|packet| packet.packet).collect()
+    }
+
+    fn packets(session: &mut Session, event: SessionEvent) -> Vec<IcmPongPacket> {
+        contents(session.handle(event).unwrap().packets)
+    }
+
+    #[test]
+    fn handshake_reaches_the_lobby() {
+        let mut session = ours(Some("me".to_string()));
+        assert_eq!(session.state(), SessionState::Idle);
+
+        assert_eq!(
+            packets(&mut session, SessionEvent::Connect),
+            [ping(Handshake::local())]
+        );
+        assert_eq!(session.state(), SessionState::PingSent);
+        assert_eq!(
+            packets(&mut session, from(PEER, ready("peer"))),
+            [ready("me")]
+        );
+        assert_eq!(session.state(), SessionState::ReadyExchanged);
+        assert_eq!(
+            session.peer(),
+            Some(&Peer {
+                client_id: PEER,
+                name: Some("peer".to_string()),
+            })
+        );
+        assert!(packets(&mut session, SessionEvent::Connect).is_empty());
+
+        assert_eq!(
+            packets(&mut session, SessionEvent::Start),
+            [IcmPongPacket::Start]
+        );
+        assert_eq!(session.state(), SessionState::Lobby);
+        assert!(packets(&mut session, from(PEER, IcmPongPacket::Start)).is_empty());
+        assert_eq!(session.state(), SessionState::InGame);
+    }
+
+    #[test]
+    fn answering_a_ping_leads_to_the_same_place() {
+        let mut session = ours(None);
+        let ping = ping(Handshake::local());
+        assert_eq!(
+            packets(&mut session, from(PEER, ping)),
+            [IcmPongPacket::Ready(Ready {
+                handshake: Handshake::local(),
+                name: None,
+            })]
+        );
+        assert_eq!(session.state(), SessionState::Idle);
+        session.handle(from(PEER, ready("peer"))).unwrap();
+        assert_eq!(session.state(), SessionState::ReadyExchanged);
+    }
+
+    #[test]
+    fn answers_to_pings_are_not_retransmitted() {
+        let start = Instant::now();
+        let mut session = ours(None);
+        session.handle_at(SessionEvent::Connect, start).unwrap();
+        for client_id in 3..300 {
+            let ping = from(client_id, ping(Handshake::local()));
+            let answer = session.handle_at(ping, start).unwrap().packets;
+            assert_eq!(answer.len(), 1);
+            assert_eq!(answer[0].sequence, 0);
+        }
+        assert!(!session.has_unacknowledged());
+        assert!(packets(&mut session, SessionEvent::Tick).is_empty());
+    }
+
+    #[test]
+    fn sessions_are_kept_apart() {
+        let mut ours = ours(None);
+        let mut theirs = Session::with_client_id(PEER, None);
+        let ping = ours.handle(SessionEvent::Connect).unwrap().packets;
+        assert_eq!(ping[0].session_id, 0);
+        let ready = theirs
+            .handle(SessionEvent::Received(ping[0].clone()))
+            .unwrap()
+            .packets;
+        assert_eq!(ready[0].session_id, session_id_for(PEER, 1));
+        assert_eq!(session_id_for(PEER, 1), session_id_for(1, PEER));
+
+        // a Ready for somebody else's Ping
+        let mut other = SessionEvent::Received(OwnedPacket {
+            session_id: session_id_for(PEER, 3),
+            ..ready[0].clone()
+        });
+        assert!(packets(&mut ours, other).is_empty());
+        assert!(ours.peer().is_none());
+
+        ours.handle(SessionEvent::Received(ready[0].clone()))
+            .unwrap();
+        assert_eq!(ours.state(), SessionState::ReadyExchanged);
+        assert_eq!(ours.session_id(), session_id_for(1, PEER));
+
+        // a packet from another game between the same two clients
+        other = SessionEvent::Received(OwnedPacket {
+            version: PROTOCOL_VERSION,
+            client_id: PEER,
+            sequence: 1,
+            timestamp: 0,
+            session_id: ours.session_id().wrapping_add(1),
+            cookie: 0,
+            token: ours.token,
+            packet: IcmPongPacket::Start,
+        });
+        assert!(packets(&mut ours, other).is_empty());
+        assert!(!ours.peer_started());
+    }
+
+    #[test]
+    fn games_between_the_same_hosts_are_kept_apart() {
+        // two clients on each host, where every packet from one host reaches
+        // both clients on the other, like on raw sockets
+        let game = |client_id, name| Session {
+            game: crate::game_tag(name),
+            ..Session::with_client_id(client_id, None)
+        };
+        let mut hosts = [
+            [game(1, "first"), game(2, "second")],
+            [game(3, "first"), game(4, "second")],
+        ];
+        let mut in_flight = Vec::new();
+        for (host, sessions) in hosts.iter_mut().enumerate() {
+            for session in sessions {
+                let ping = session.handle(SessionEvent::Connect).unwrap().packets;
+                in_flight.extend(ping.into_iter().map(|packet| (1 - host, packet)));
+            }
+        }
+        while let Some((host, packet)) = in_flight.pop() {
+            // the client in the other game sees each packet first
+            for session in hosts[host].iter_mut().rev() {
+                let transition = session
+                    .handle(SessionEvent::Received(packet.clone()))
+                    .unwrap();
+                in_flight.extend(
+                    transition
+                        .packets
+                        .into_iter()
+                        .map(|packet| (1 - host, packet)),
+                );
+            }
+        }
+
+        let peer = |session: &Session| session.peer().unwrap().client_id;
+        assert_eq!(
+            hosts.map(|sessions| sessions.map(|session| peer(&session))),
+            [[3, 4], [1, 2]]
+        );
+        assert_eq!(crate::game_tag("first"), crate::game_tag("first"));
+        assert_ne!(crate::game_tag("first"), crate::game_tag("second"));
+    }
+
+    #[test]
+    fn spoofed_packets_need_the_cookie() {
+        let mut ours = ours(None);
+        ours.handle(SessionEvent::Connect).unwrap();
+
+        // a Ready from someone who never saw our Ping
+        let mut spoofed = sequenced(PEER, 1, ready("mallory"));
+        if let SessionEvent::Received(packet) = &mut spoofed {
+            packet.token = COOKIE.wrapping_add(1);
+        }
+        assert!(packets(&mut ours, spoofed).is_empty());
+        assert!(ours.peer().is_none());
+
+        // or one in a newer version, which might not carry the cookie at all
+        let newer = Handshake {
+            minimum_version: PROTOCOL_VERSION,
+            maximum_version: 200,
+            features: 0,
+        };
+        let mut spoofed = OwnedPacket {
+            version: 200,
+            client_id: PEER,
+            sequence: 0,
+            timestamp: 0,
+            session_id: 0,
+            cookie: 0,
+            token: 0,
+            packet: IcmPongPacket::Ready(Ready {
+                handshake: newer,
+                name: Some("mallory".to_string()),
+            }),
+        };
+        assert!(packets(&mut ours, SessionEvent::Received(spoofed.clone())).is_empty());
+        assert!(ours.peer().is_none());
+        assert_eq!(ours.state(), SessionState::PingSent);
+        // it can only tell us that we might not be able to play
+        let incompatible = Handshake {
+            minimum_version: 200,
+            ..newer
+        };
+        spoofed.packet = IcmPongPacket::Ready(Ready {
+            handshake: incompatible,
+            name: None,
+        });
+        assert!(packets(&mut ours, SessionEvent::Received(spoofed)).is_empty());
+        assert!(ours.peer().is_none());
+        assert_eq!(ours.state(), SessionState::PingSent);
+        assert!(matches!(
+            ours.incompatibility(),
+            Some(IcmPongError::IncompatibleVersion(handshake)) if *handshake == incompatible
+        ));
+        // and so can a Ping, whatever its version
+        let mut spoofed = from(PEER, ping(incompatible));
+        if let SessionEvent::Received(packet) = &mut spoofed {
+            packet.version = 200;
+            packet.session_id = 0;
+        }
+        assert_eq!(packets(&mut ours, spoofed), [anonymous_ready()]);
+        assert!(ours.peer().is_none());
+        assert_eq!(ours.version(), PROTOCOL_VERSION);
+
+        ours.handle(sequenced(PEER, 1, ready("peer"))).unwrap();
+        assert_eq!(ours.state(), SessionState::ReadyExchanged);
+
+        // later packets need the token made from both cookies
+        let mut spoofed = sequenced(PEER, 2, IcmPongPacket::Start);
+        if let SessionEvent::Received(packet) = &mut spoofed {
+            packet.token = PEER_COOKIE;
+        }
+        assert!(packets(&mut ours, spoofed).is_empty());
+        assert!(!ours.peer_started());
+        ours.handle(sequenced(PEER, 2, IcmPongPacket::Start))
+            .unwrap();
+        assert!(ours.peer_started());
+    }
+
+    #[test]
+    fn both_sides_have_to_encrypt() {
+        let encrypted = Handshake {
+            features: FEATURE_ENCRYPTION,
+            ..Handshake::local()
+        };
+        let mut session = ours(None);
+        // Pings are still answered, so that the other side finds out too
+        assert_eq!(
+            packets(&mut session, from(PEER, ping(encrypted))),
+            [anonymous_ready()]
+        );
+        assert!(matches!(
+            session.incompatibility(),
+            Some(IcmPongError::EncryptionMismatch(true))
+        ));
+        session.handle(SessionEvent::Connect).unwrap();
+        assert!(matches!(
+            session.handle(from(
+                PEER,
+                IcmPongPacket::Ready(Ready {
+                    handshake: encrypted,
+                    name: None,
+                })
+            )),
+            Err(IcmPongError::EncryptionMismatch(true))
+        ));
+
+        let mut session = ours(None);
+        session.encrypt = true;
+        session
+            .handle(from(PEER, ping(Handshake::local())))
+            .unwrap();
+        assert!(matches!(
+            session.incompatibility(),
+            Some(IcmPongError::EncryptionMismatch(false))
+        ));
+        assert_eq!(
+            packets(&mut session, from(PEER, ping(encrypted))),
+            [IcmPongPacket::Ready(Ready {
+                handshake: encrypted,
+                name: None,
+            })]
+        );
+        // only the Ready echoing our cookie settles the features
+        assert_eq!(session.features(), 0);
+        session.handle(SessionEvent::Connect).unwrap();
+        session
+            .handle(from(
+                PEER,
+                IcmPongPacket::Ready(Ready {
+                    handshake: encrypted,
+                    name: None,
+                }),
+            ))
+            .unwrap();
+        assert_eq!(session.features(), FEATURE_ENCRYPTION);
+    }
+
+    #[test]
+    fn peer_can_start_first() {
+        let mut session = established();
+        session.handle(from(PEER, IcmPongPacket::Start)).unwrap();
+        assert!(session.peer_started());
+        assert_eq!(session.state(), SessionState::ReadyExchanged);
+        session.handle(SessionEvent::Start).unwrap();
+        assert_eq!(session.state(), SessionState::InGame);
+    }
+
+    #[test]
+    fn duplicates_are_answered_or_ignored() {
+        let mut session = established();
+        // the peer didn't get our Ready yet, so it asks again
+        assert_eq!(
+            packets(&mut session, from(PEER, ping(Handshake::local()))).len(),
+            1
+        );
+        assert!(packets(&mut session, from(PEER, ready("peer"))).is_empty());
+        assert_eq!(session.state(), SessionState::ReadyExchanged);
+
+        session.handle(SessionEvent::Start).unwrap();
+        session.handle(from(PEER, IcmPongPacket::Start)).unwrap();
+        session.handle(from(PEER, IcmPongPacket::Start)).unwrap();
+        assert_eq!(session.state(), SessionState::InGame);
+        assert!(packets(&mut session, SessionEvent::Start).is_empty());
+    }
+
+    #[test]
+    fn out_of_order_packets_are_ignored_until_established() {
+        let mut session = ours(None);
+        session.handle(SessionEvent::Connect).unwrap();
+        for packet in [
+            IcmPongPacket::Start,
+            IcmPongPacket::ScoreUpdate(ScoreUpdate { score: [1, 0] }),
+            IcmPongPacket::Disconnect,
+        ] {
+            assert_eq!(
+                session.handle(from(PEER, packet)).unwrap(),
+                Transition::default()
+            );
+        }
+        assert_eq!(session.state(), SessionState::PingSent);
+        assert!(!session.peer_started());
+        assert!(packets(&mut session, SessionEvent::Start).is_empty());
+        assert_eq!(session.state(), SessionState::PingSent);
+    }
+
+    #[test]
+    fn only_the_peer_is_listened_to() {
+        let mut session = established();
+        assert!(packets(&mut session, from(1, IcmPongPacket::Disconnect)).is_empty());
+        assert!(packets(&mut session, from(3, IcmPongPacket::Disconnect)).is_empty());
+        assert!(packets(&mut session, from(3, ready("stranger"))).is_empty());
+        assert_eq!(session.state(), SessionState::ReadyExchanged);
+        assert_eq!(session.peer().unwrap().client_id, PEER);
+
+        let paddle = IcmPongPacket::PaddlePosition(PaddlePosition {
+            x_position: 3,
+            y_position: 4,
+        });
+        assert_eq!(
+            session
+                .handle(sequenced(PEER, 1, paddle.clone()))
+                .unwrap()
+                .update,
+            Some(paddle)
+        );
+    }
+
+    #[test]
+    fn stale_state_updates_are_dropped() {
+        let mut session = established();
+        let paddle = |y_position| {
+            IcmPongPacket::PaddlePosition(PaddlePosition {
+                x_position: 3,
+                y_position,
+            })
+        };
+        let ball = IcmPongPacket::BallUpdate(BallUpdate {
+            x_position: 40,
+            y_position: 12,
+            x_movement: 0.5,
+            y_movement: 0.5,
+            xf32: 40.0,
+            yf32: 12.0,
+        });
+        let update = |session: &mut Session, sequence, packet| {
+            session
+                .handle(sequenced(PEER, sequence, packet))
+                .unwrap()
+                .update
+        };
+        assert_eq!(update(&mut session, 2, paddle(5)), Some(paddle(5)));
+        assert_eq!(update(&mut session, 1, paddle(4)), None);
+        assert_eq!(update(&mut session, 2, paddle(5)), None);
+        // paddles and the ball are tracked separately
+        assert_eq!(update(&mut session, 1, ball.clone()), Some(ball));
+        assert_eq!(update(&mut session, 3, paddle(6)), Some(paddle(6)));
+        // and aren't acknowledged
+        assert!(packets(&mut session, sequenced(PEER, 4, paddle(7))).is_empty());
+
+        let sent = session.outgoing(paddle(1), Instant::now());
+        let next = session.outgoing(paddle(2), Instant::now());
+        assert!(next.sequence > sent.sequence);
+        assert!(next.timestamp >= sent.timestamp);
+        assert!(!session.has_unacknowledged());
+    }
+
+    #[test]
+    fn disconnects_close_the_session() {
+        let mut session = established();
+        session.handle(SessionEvent::Start).unwrap();
+        assert_eq!(
+            packets(&mut session, sequenced(PEER, 1, IcmPongPacket::Disconnect)),
+            [ack(1)]
+        );
+        assert_eq!(session.state(), SessionState::Closing);
+        // the peer is gone, so our Start doesn't need to arrive anymore
+        assert!(!session.has_unacknowledged());
+        assert_eq!(
+            packets(&mut session, sequenced(PEER, 1, IcmPongPacket::Disconnect)),
+            [ack(1)]
+        );
+        assert!(packets(&mut session, SessionEvent::Connect).is_empty());
+
+        let mut session = established();
+        let quit = session.handle(SessionEvent::Quit).unwrap().packets;
+        assert_eq!(contents(quit.clone()), [IcmPongPacket::Disconnect]);
+        assert_eq!(session.state(), SessionState::Closing);
+        assert!(session.has_unacknowledged());
+        session.handle(from(PEER, ack(quit[0].sequence))).unwrap();
+        assert!(!session.has_unacknowledged());
+
+        let mut session = ours(None);
+        assert!(packets(&mut session, SessionEvent::Quit).is_empty());
+        assert_eq!(session.state(), SessionState::Closing);
+    }
+
+    #[test]
+    fn reliable_packets_are_retransmitted_until_acknowledged() {
+        let start = Instant::now();
+        let mut session = established();
+        let sent = session
+            .handle_at(SessionEvent::Start, start)
+            .unwrap()
+            .packets;
+        assert_eq!(sent.len(), 1);
+        let sequence = sent[0].sequence;
+        assert_ne!(sequence, 0);
+
+        let retransmitted = |session: &mut Session, after| {
+            session
+                .handle_at(SessionEvent::Tick, start + after)
+                .unwrap()
+                .packets
+                .into_iter()
+                .filter(|packet| packet.packet == IcmPongPacket::Start)
+                .collect::<Vec<_>>()
+        };
+        assert!(retransmitted(&mut session, RETRANSMIT_INTERVAL / 2).is_empty());
+        assert_eq!(retransmitted(&mut session, RETRANSMIT_INTERVAL), sent);
+        // pressing start again doesn't make it a different packet
+        assert_eq!(
+            session
+                .handle_at(SessionEvent::Start, start + RETRANSMIT_INTERVAL)
+                .unwrap()
+                .packets,
+            sent
+        );
+
+        session.handle(from(PEER, ack(sequence))).unwrap();
+        assert!(!session.has_unacknowledged());
+        assert!(retransmitted(&mut session, RETRANSMIT_INTERVAL * 4).is_empty());
+    }
+
+    #[test]
+    fn duplicate_sequences_are_acknowledged_but_dropped() {
+        let mut session = established();
+        let score = IcmPongPacket::ScoreUpdate(ScoreUpdate { score: [1, 0] });
+        let first = session.handle(sequenced(PEER, 5, score.clone())).unwrap();
+        assert_eq!(contents(first.packets), [ack(5)]);
+        assert_eq!(first.update, Some(score.clone()));
+
+        let again = session.handle(sequenced(PEER, 5, score.clone())).unwrap();
+        assert_eq!(contents(again.packets), [ack(5)]);
+        assert_eq!(again.update, None);
+
+        // late, but not seen before: acknowledged, but older than the score
+        // applied already
+        let late = session.handle(sequenced(PEER, 3, score.clone())).unwrap();
+        assert_eq!(contents(late.packets), [ack(3)]);
+        assert_eq!(late.update, None);
+        let later = IcmPongPacket::ScoreUpdate(ScoreUpdate { score: [2, 0] });
+        let next = session.handle(sequenced(PEER, 6, later.clone())).unwrap();
+        assert_eq!(next.update, Some(later));
+        let far_ahead = 5 + RECEIVE_WINDOW;
+        session
+            .handle(sequenced(PEER, far_ahead, score.clone()))
+            .unwrap();
+        let too_old = session.handle(sequenced(PEER, 4, score)).unwrap();
+        assert_eq!(contents(too_old.packets), [ack(4)]);
+        assert_eq!(too_old.update, None);
+    }
+
+    #[test]
+    fn silent_peers_time_out() {
+        let start = Instant::now();
+        let mut session = ours(None);
+        session.peer_timeout = Duration::from_secs(5);
+        assert!(packets(&mut session, SessionEvent::Tick).is_empty());
+        session.handle_at(SessionEvent::Connect, start).unwrap();
+        session.handle_at(from(PEER, ready("peer")), start).unwrap();
+        session.handle_at(from(PEER, ack(1)), start).unwrap();
+
+        let tick = |session: &mut Session, seconds| {
+            contents(
+                session
+                    .handle_at(SessionEvent::Tick, start + Duration::from_secs(seconds))
+                    .unwrap()
+                    .packets,
+            )
+        };
+        assert_eq!(tick(&mut session, 0), [IcmPongPacket::Heartbeat]);
+        assert!(session
+            .handle_at(SessionEvent::Tick, start + HEARTBEAT_INTERVAL / 2)
+            .unwrap()
+            .packets
+            .is_empty());
+        assert_eq!(tick(&mut session, 1), [IcmPongPacket::Heartbeat]);
+        assert_eq!(
+            session.connection_lost(start + Duration::from_secs(1)),
+            None
+        );
+        assert_eq!(
+            session.connection_lost(start + Duration::from_secs(3)),
+            Some(Duration::from_secs(2))
+        );
+
+        // hearing from the peer resets the clock
+        session
+            .handle_at(
+                from(PEER, IcmPongPacket::Heartbeat),
+                start + Duration::from_secs(3),
+            )
+            .unwrap();
+        assert_eq!(
+            session.connection_lost(start + Duration::from_secs(4)),
+            None
+        );
+        assert_eq!(tick(&mut session, 7), [IcmPongPacket::Heartbeat]);
+        assert_eq!(session.state(), SessionState::ReadyExchanged);
+
+        assert_eq!(tick(&mut session, 8), [IcmPongPacket::Disconnect]);
+        assert_eq!(session.state(), SessionState::Closing);
+        assert!(session.timed_out());
+        assert!(tick(&mut session, 9).is_empty());
+    }
+
+    #[test]
+    fn versions_are_checked() {
+        let mut session = ours(None);
+        let too_new = Handshake {
+            minimum_version: PROTOCOL_VERSION + 1,
+            maximum_version: PROTOCOL_VERSION + 1,
+            features: 0,
+        };
+        assert_eq!(
+            packets(&mut session, from(PEER, ping(too_new))),
+            [anonymous_ready()]
+        );
+        assert!(matches!(
+            session.incompatibility(),
+            Some(IcmPongError::IncompatibleVersion(handshake)) if *handshake == too_new
+        ));
+        assert_eq!(session.state(), SessionState::Idle);
+        session.handle(SessionEvent::Connect).unwrap();
+        let ready = IcmPongPacket::Ready(Ready {
+            handshake: too_new,
+            name: None,
+        });
+        assert!(matches!(
+            session.handle(from(PEER, ready)),
+            Err(IcmPongError::IncompatibleVersion(handshake)) if handshake == too_new
+        ));
+
+        let mut session = established();
+        assert_eq!(session.version(), PROTOCOL_VERSION);
+        let result = session.handle(SessionEvent::Received(OwnedPacket {
+            version: MINIMUM_PROTOCOL_VERSION - 1,
+            client_id: PEER,
+            sequence: 0,
+            timestamp: 0,
+            session_id: session.session_id(),
+            cookie: 0,
+            token: session.token,
+            packet: IcmPongPacket::Start,
+        }));
+        assert!(matches!(
+            result,
+            Err(IcmPongError::VersionMismatch(version)) if version == MINIMUM_PROTOCOL_VERSION - 1
+        ));
+    }
+}
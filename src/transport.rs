@@ -0,0 +1,713 @@
+use pnet::packet::{
+    icmp::{self, IcmpPacket, IcmpTypes},
+    icmpv6::Icmpv6Types,
+};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::{
+    ffi::{CStr, CString},
+    mem::MaybeUninit,
+    net::{AddrParseError, IpAddr, SocketAddr, SocketAddrV6, ToSocketAddrs},
+    os::fd::AsRawFd,
+    str::FromStr,
+    sync::{
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Where ICMPong packets are sent to and received from. ICMP has no ports, so
+/// this is an IP address plus, for link-local IPv6 addresses, the index of the
+/// interface (zone) it's valid on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerAddress {
+    pub ip: IpAddr,
+    /// 0 if the address isn't scoped to an interface.
+    pub scope_id: u32,
+}
+
+#[derive(Debug)]
+pub enum PeerAddressError {
+    InvalidAddress(AddrParseError),
+    UnknownInterface(String),
+    ZoneWithoutLinkLocal(IpAddr),
+    Unresolvable(String, std::io::Error),
+}
+
+impl std::fmt::Display for PeerAddressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidAddress(error) => write!(f, "{error}"),
+            Self::UnknownInterface(interface) => write!(f, "unknown interface: {interface}"),
+            Self::ZoneWithoutLinkLocal(ip) => {
+                write!(f, "only link-local IPv6 addresses can have a zone: {ip}")
+            }
+            Self::Unresolvable(host, error) => write!(f, "unable to resolve {host}: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for PeerAddressError {}
+
+impl PeerAddress {
+    pub fn new(ip: IpAddr) -> Self {
+        Self { ip, scope_id: 0 }
+    }
+
+    pub fn is_link_local(&self) -> bool {
+        match self.ip {
+            IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+            IpAddr::V4(_) => false,
+        }
+    }
+
+    /// Scopes a link-local address to `interface`, unless it already has a
+    /// zone of its own.
+    pub fn with_interface(self, interface: &str) -> Result<Self, PeerAddressError> {
+        if !self.is_link_local() || self.scope_id != 0 {
+            return Ok(self);
+        }
+        Ok(Self {
+            scope_id: interface_index(interface)?,
+            ..self
+        })
+    }
+
+    /// Parses `host` as an address, or looks it up through the system resolver
+    /// (which includes `/etc/hosts`) if it isn't one.
+    pub fn resolve(host: &str) -> Result<Vec<Self>, PeerAddressError> {
+        match Self::from_str(host) {
+            Ok(peer) => Ok(vec![peer]),
+            Err(PeerAddressError::InvalidAddress(_)) => {
+                let mut peers = Vec::new();
+                for address in (host, 0)
+                    .to_socket_addrs()
+                    .map_err(|error| PeerAddressError::Unresolvable(host.to_string(), error))?
+                {
+                    let peer = Self::from(address);
+                    if !peers.contains(&peer) {
+                        peers.push(peer);
+                    }
+                }
+                Ok(peers)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Whether a packet received from `source` came from this peer.
+    pub fn matches(&self, source: &PeerAddress) -> bool {
+        self.ip == source.ip && (self.scope_id == 0 || self.scope_id == source.scope_id)
+    }
+}
+
+impl FromStr for PeerAddress {
+    type Err = PeerAddressError;
+
+    /// Parses an IP address, optionally followed by a zone (`fe80::1%eth0` or
+    /// `fe80::1%2`).
+    fn from_str(address: &str) -> Result<Self, Self::Err> {
+        let (ip, zone) = match address.split_once('%') {
+            Some((ip, zone)) => (ip, Some(zone)),
+            None => (address, None),
+        };
+        let peer = Self::new(IpAddr::from_str(ip).map_err(PeerAddressError::InvalidAddress)?);
+        match zone {
+            Some(_) if !peer.is_link_local() => {
+                Err(PeerAddressError::ZoneWithoutLinkLocal(peer.ip))
+            }
+            Some(zone) => Ok(Self {
+                scope_id: match zone.parse() {
+                    Ok(scope_id) => scope_id,
+                    Err(_) => interface_index(zone)?,
+                },
+                ..peer
+            }),
+            None => Ok(peer),
+        }
+    }
+}
+
+impl std::fmt::Display for PeerAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.ip)?;
+        if self.scope_id != 0 {
+            let mut name = [0; libc::IF_NAMESIZE];
+            if unsafe { libc::if_indextoname(self.scope_id, name.as_mut_ptr()) }.is_null() {
+                write!(f, "%{}", self.scope_id)?;
+            } else {
+                let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+                write!(f, "%{}", name.to_string_lossy())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<SocketAddr> for PeerAddress {
+    fn from(address: SocketAddr) -> Self {
+        match address {
+            SocketAddr::V4(address) => Self::new(IpAddr::V4(*address.ip())),
+            SocketAddr::V6(address) => Self {
+                ip: IpAddr::V6(*address.ip()),
+                scope_id: address.scope_id(),
+            },
+        }
+    }
+}
+
+impl From<PeerAddress> for SocketAddr {
+    fn from(peer: PeerAddress) -> Self {
+        match peer.ip {
+            IpAddr::V4(ip) => SocketAddr::new(IpAddr::V4(ip), 0),
+            IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, 0, 0, peer.scope_id)),
+        }
+    }
+}
+
+fn interface_index(interface: &str) -> Result<u32, PeerAddressError> {
+    let unknown_interface = || PeerAddressError::UnknownInterface(interface.to_string());
+    let name = CString::new(interface).map_err(|_| unknown_interface())?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(unknown_interface()),
+        index => Ok(index),
+    }
+}
+
+/// The size of an echo request's type, code, checksum, identifier and sequence
+/// number fields, which come before the payload.
+pub const ECHO_HEADER_SIZE: usize = 8;
+
+/// An ICMP or ICMPv6 echo request, without the fields the transport fills in
+/// (type, code and checksum).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EchoRequest {
+    pub identifier: u16,
+    pub sequence: u16,
+    pub payload: Vec<u8>,
+}
+
+/// Moves echo requests carrying ICMPong payloads between this host and its
+/// peers.
+///
+/// All methods take `&self` so that one thread can wait in
+/// [`Transport::receive_timeout`] while another keeps sending.
+pub trait Transport: Send + Sync {
+    fn send_to(&self, request: &EchoRequest, destination: &PeerAddress) -> std::io::Result<()>;
+
+    /// Waits up to `timeout` for the next echo request, returning `None` if
+    /// none arrived (or the wait was interrupted). Anything else (like echo
+    /// replies) is skipped.
+    fn receive_timeout(
+        &self,
+        timeout: Duration,
+    ) -> std::io::Result<Option<(EchoRequest, PeerAddress)>>;
+
+    /// Waits for the next echo request, however long it takes.
+    fn receive_from(&self) -> std::io::Result<(EchoRequest, PeerAddress)> {
+        loop {
+            if let Some(received) = self.receive_timeout(Duration::from_secs(1))? {
+                return Ok(received);
+            }
+        }
+    }
+}
+
+/// `ICMP_FILTER` from `<linux/icmp.h>`, which libc doesn't have.
+#[cfg(target_os = "linux")]
+const ICMP_FILTER: libc::c_int = 1;
+/// `ICMP6_FILTER` from `<linux/icmpv6.h>`, which libc doesn't have either.
+#[cfg(target_os = "linux")]
+const ICMP6_FILTER: libc::c_int = 1;
+
+/// Raw ICMP (for IPv4 peers) and/or ICMPv6 sockets. Requires root or
+/// `CAP_NET_RAW`.
+///
+/// On Linux, the kernel only hands them echo requests that carry the magic,
+/// and, if the peer has just one address of the socket's family, only ones
+/// from that address. Everything else a raw socket would get (neighbor
+/// discovery, router advertisements, the replies to our own requests, other
+/// people's pings) never wakes us up.
+pub struct IcmpTransport {
+    ipv4: Option<Socket>,
+    ipv6: Option<Socket>,
+}
+
+impl IcmpTransport {
+    /// Opens a socket for each address family used by `peers`, optionally
+    /// bound to `interface` so that only packets arriving there are received.
+    pub fn new(peers: &[PeerAddress], interface: Option<&str>) -> std::io::Result<Self> {
+        let open_socket = |domain, protocol| -> std::io::Result<Socket> {
+            let socket = Socket::new(domain, Type::RAW, Some(protocol))?;
+            if let Some(interface) = interface {
+                #[cfg(target_os = "linux")]
+                socket.bind_device(Some(interface.as_bytes()))?;
+                #[cfg(not(target_os = "linux"))]
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!("binding to an interface ({interface}) is only supported on Linux"),
+                ));
+            }
+            #[cfg(target_os = "linux")]
+            filter_echo_requests(&socket, domain == Domain::IPV6)?;
+            let mut addresses = peers
+                .iter()
+                .filter(|peer| peer.ip.is_ipv6() == (domain == Domain::IPV6));
+            if let (Some(peer), None) = (addresses.next(), addresses.next()) {
+                // raw sockets only receive from the address they're connected
+                // to, and can still send anywhere
+                socket.connect(&SockAddr::from(SocketAddr::from(*peer)))?;
+            }
+            Ok(socket)
+        };
+        Ok(Self {
+            ipv4: match peers.iter().any(|peer| peer.ip.is_ipv4()) {
+                true => Some(open_socket(Domain::IPV4, Protocol::ICMPV4)?),
+                false => None,
+            },
+            ipv6: match peers.iter().any(|peer| peer.ip.is_ipv6()) {
+                true => Some(open_socket(Domain::IPV6, Protocol::ICMPV6)?),
+                false => None,
+            },
+        })
+    }
+
+    /// Waits up to `timeout` until one of the sockets has a packet to read,
+    /// and returns it along with whether it's the ICMPv6 one.
+    fn readable_socket(&self, timeout: Duration) -> std::io::Result<Option<(&Socket, bool)>> {
+        let sockets: Vec<_> = [(&self.ipv4, false), (&self.ipv6, true)]
+            .into_iter()
+            .filter_map(|(socket, ipv6)| Some((socket.as_ref()?, ipv6)))
+            .collect();
+        if sockets.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "no ICMP sockets are open",
+            ));
+        }
+        let mut fds: Vec<_> = sockets
+            .iter()
+            .map(|(socket, _)| libc::pollfd {
+                fd: socket.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        // rounded up, so that short timeouts don't become busy loops
+        let timeout = timeout.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32;
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } < 0 {
+            let error = std::io::Error::last_os_error();
+            return match error.kind() {
+                std::io::ErrorKind::Interrupted => Ok(None),
+                _ => Err(error),
+            };
+        }
+        Ok(fds
+            .iter()
+            .zip(sockets)
+            .find(|(fd, _)| fd.revents != 0)
+            .map(|(_, socket)| socket))
+    }
+}
+
+/// Makes the kernel drop everything but echo requests carrying
+/// [`crate::PACKET_MAGIC`] before they reach `socket`, first by ICMP type and
+/// then with a classic BPF program that looks for the magic.
+#[cfg(target_os = "linux")]
+fn filter_echo_requests(socket: &Socket, ipv6: bool) -> std::io::Result<()> {
+    let set_option = |level, name, value: &[u32]| {
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                value.as_ptr().cast(),
+                std::mem::size_of_val(value) as libc::socklen_t,
+            )
+        };
+        match result {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    };
+    // a set bit blocks the type
+    if ipv6 {
+        let mut blocked = [u32::MAX; 8];
+        let echo_request = Icmpv6Types::EchoRequest.0 as usize;
+        blocked[echo_request / 32] &= !(1 << (echo_request % 32));
+        set_option(libc::IPPROTO_ICMPV6, ICMP6_FILTER, &blocked)?;
+    } else {
+        set_option(
+            libc::SOL_RAW,
+            ICMP_FILTER,
+            &[!(1 << IcmpTypes::EchoRequest.0)],
+        )?;
+    }
+    socket.attach_filter(&echo_request_filter(ipv6))
+}
+
+/// A classic BPF program accepting echo requests with [`crate::PACKET_MAGIC`]
+/// at the start of their payload, or (for packets of protocol versions before
+/// 7, so that old peers are still recognized) in their identifier and
+/// sequence number. Packets on raw IPv4 sockets start with the IP header,
+/// which is skipped.
+#[cfg(target_os = "linux")]
+fn echo_request_filter(ipv6: bool) -> Vec<libc::sock_filter> {
+    let statement = |code: u32, k: u32| libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let jump_if_equal = |k: u32, jt: u8, jf: u8| libc::sock_filter {
+        code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+        jt,
+        jf,
+        k,
+    };
+    let load_at = |size: u32, offset: u32| statement(libc::BPF_LD | size | libc::BPF_IND, offset);
+    let magic = crate::PACKET_MAGIC;
+    // each check jumps to the next one if the magic isn't at `offset`, or to
+    // the end if it is
+    let magic_at = |offset: u32, instructions_left: u8| {
+        [
+            load_at(libc::BPF_W, offset),
+            jump_if_equal(u32::from_be_bytes(magic[0..4].try_into().unwrap()), 0, 4),
+            load_at(libc::BPF_H, offset + 4),
+            jump_if_equal(u16::from_be_bytes([magic[4], magic[5]]) as u32, 0, 2),
+            load_at(libc::BPF_B, offset + 6),
+            jump_if_equal(magic[6] as u32, instructions_left, 0),
+        ]
+    };
+    let (load_header_length, echo_request) = if ipv6 {
+        (
+            statement(libc::BPF_LDX | libc::BPF_IMM, 0),
+            Icmpv6Types::EchoRequest.0,
+        )
+    } else {
+        // 4 * the IHL in the first byte
+        (
+            statement(libc::BPF_LDX | libc::BPF_B | libc::BPF_MSH, 0),
+            IcmpTypes::EchoRequest.0,
+        )
+    };
+    let mut program = vec![
+        load_header_length,
+        load_at(libc::BPF_B, 0),
+        jump_if_equal(echo_request as u32, 0, 12),
+    ];
+    program.extend(magic_at(ECHO_HEADER_SIZE as u32, 7));
+    program.extend(magic_at(4, 1));
+    program.extend([
+        statement(libc::BPF_RET | libc::BPF_K, 0),
+        statement(libc::BPF_RET | libc::BPF_K, u32::MAX),
+    ]);
+    program
+}
+
+impl Transport for IcmpTransport {
+    fn send_to(&self, request: &EchoRequest, destination: &PeerAddress) -> std::io::Result<()> {
+        let mut buffer = [
+            [0; 4].as_slice(),
+            &request.identifier.to_be_bytes(),
+            &request.sequence.to_be_bytes(),
+            &request.payload,
+        ]
+        .concat();
+        let socket = if destination.ip.is_ipv6() {
+            // the kernel fills in the checksum of ICMPv6 packets
+            buffer[0] = Icmpv6Types::EchoRequest.0;
+            &self.ipv6
+        } else {
+            buffer[0] = IcmpTypes::EchoRequest.0;
+            let checksum = icmp::checksum(&IcmpPacket::new(&buffer).unwrap());
+            buffer[2..4].copy_from_slice(&checksum.to_be_bytes());
+            &self.ipv4
+        };
+        let Some(socket) = socket else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("no ICMP socket is open for {destination}"),
+            ));
+        };
+        socket.send_to(&buffer, &SockAddr::from(SocketAddr::from(*destination)))?;
+        Ok(())
+    }
+
+    fn receive_timeout(
+        &self,
+        timeout: Duration,
+    ) -> std::io::Result<Option<(EchoRequest, PeerAddress)>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let Some((socket, ipv6)) = self.readable_socket(remaining)? else {
+                return Ok(None);
+            };
+            let mut buffer = [MaybeUninit::<u8>::uninit(); 1500];
+            let (length, source) = socket.recv_from(&mut buffer)?;
+            // SAFETY: recv_from initialized the first `length` bytes
+            let packet =
+                unsafe { std::slice::from_raw_parts(buffer.as_ptr() as *const u8, length) };
+            let (icmp_packet, echo_request) = if ipv6 {
+                (packet, Icmpv6Types::EchoRequest.0)
+            } else {
+                // raw IPv4 sockets also hand us the IP header
+                let header_length = packet.first().map_or(0, |byte| (byte & 0x0f) as usize * 4);
+                (
+                    packet.get(header_length..).unwrap_or_default(),
+                    IcmpTypes::EchoRequest.0,
+                )
+            };
+            // raw ICMPv6 sockets get every type, including the replies the
+            // kernel sends to our own requests (unless filtered out already)
+            if icmp_packet.len() < ECHO_HEADER_SIZE || icmp_packet[0] != echo_request {
+                continue;
+            }
+            let source = source.as_socket().ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "received a packet from a non-IP address",
+                )
+            })?;
+            let request = EchoRequest {
+                identifier: u16::from_be_bytes([icmp_packet[4], icmp_packet[5]]),
+                sequence: u16::from_be_bytes([icmp_packet[6], icmp_packet[7]]),
+                payload: icmp_packet[ECHO_HEADER_SIZE..].to_owned(),
+            };
+            return Ok(Some((request, source.into())));
+        }
+    }
+}
+
+/// An in-process transport, useful for running two clients against each other
+/// without a socket.
+pub struct LoopbackTransport {
+    address: PeerAddress,
+    tx: Mutex<Sender<(EchoRequest, PeerAddress)>>,
+    rx: Mutex<Receiver<(EchoRequest, PeerAddress)>>,
+}
+
+impl LoopbackTransport {
+    /// Creates two connected transports. Whatever one side sends is received
+    /// by the other, with the sender's address as the source.
+    pub fn pair(first_address: PeerAddress, second_address: PeerAddress) -> (Self, Self) {
+        let (first_tx, first_rx) = channel();
+        let (second_tx, second_rx) = channel();
+        (
+            Self {
+                address: first_address,
+                tx: Mutex::new(second_tx),
+                rx: Mutex::new(first_rx),
+            },
+            Self {
+                address: second_address,
+                tx: Mutex::new(first_tx),
+                rx: Mutex::new(second_rx),
+            },
+        )
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn send_to(&self, request: &EchoRequest, _destination: &PeerAddress) -> std::io::Result<()> {
+        self.tx
+            .lock()
+            .unwrap()
+            .send((request.clone(), self.address))
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))
+    }
+
+    fn receive_timeout(
+        &self,
+        timeout: Duration,
+    ) -> std::io::Result<Option<(EchoRequest, PeerAddress)>> {
+        match self.rx.lock().unwrap().recv_timeout(timeout) {
+            Ok(received) => Ok(Some(received)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(std::io::Error::from(std::io::ErrorKind::BrokenPipe))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link_local(scope_id: u32) -> PeerAddress {
+        PeerAddress {
+            ip: "fe80::1".parse().unwrap(),
+            scope_id,
+        }
+    }
+
+    #[test]
+    fn zones_are_parsed() {
+        let loopback = interface_index("lo").unwrap();
+        assert_eq!(
+            PeerAddress::from_str("fe80::1%lo").unwrap(),
+            link_local(loopback)
+        );
+        assert_eq!(PeerAddress::from_str("fe80::1%2").unwrap(), link_local(2));
+        assert_eq!(PeerAddress::from_str("fe80::1").unwrap(), link_local(0));
+        assert!(matches!(
+            PeerAddress::from_str("2001:db8::1%eth0"),
+            Err(PeerAddressError::ZoneWithoutLinkLocal(ip)) if ip == "2001:db8::1".parse::<IpAddr>().unwrap()
+        ));
+        assert!(matches!(
+            PeerAddress::from_str("fe80::1%no-such-interface"),
+            Err(PeerAddressError::UnknownInterface(interface)) if interface == "no-such-interface"
+        ));
+        assert!(matches!(
+            PeerAddress::from_str("not an address"),
+            Err(PeerAddressError::InvalidAddress(_))
+        ));
+    }
+
+    #[test]
+    fn only_link_local_addresses_take_an_interface() {
+        assert!(link_local(0).is_link_local());
+        assert!(PeerAddress::from_str("febf::1").unwrap().is_link_local());
+        assert!(!PeerAddress::from_str("fec0::1").unwrap().is_link_local());
+        assert!(!PeerAddress::from_str("127.0.0.1").unwrap().is_link_local());
+
+        let loopback = interface_index("lo").unwrap();
+        assert_eq!(
+            link_local(0).with_interface("lo").unwrap(),
+            link_local(loopback)
+        );
+        // a zone given with the address wins
+        assert_eq!(link_local(2).with_interface("lo").unwrap(), link_local(2));
+        let global = PeerAddress::from_str("2001:db8::1").unwrap();
+        assert_eq!(global.with_interface("lo").unwrap(), global);
+        assert!(matches!(
+            link_local(0).with_interface("no-such-interface"),
+            Err(PeerAddressError::UnknownInterface(_))
+        ));
+    }
+
+    #[test]
+    fn sources_match_on_address_and_zone() {
+        assert!(link_local(2).matches(&link_local(2)));
+        assert!(!link_local(2).matches(&link_local(3)));
+        // an unscoped peer doesn't care where its packets arrive
+        assert!(link_local(0).matches(&link_local(3)));
+        let other = PeerAddress {
+            ip: "fe80::2".parse().unwrap(),
+            scope_id: 2,
+        };
+        assert!(!link_local(2).matches(&other));
+    }
+
+    #[test]
+    fn hostnames_resolve_to_every_address_once() {
+        // addresses are taken as they are, zone included
+        assert_eq!(
+            PeerAddress::resolve("fe80::1%2").unwrap(),
+            vec![link_local(2)]
+        );
+
+        // localhost comes back once per socket type from getaddrinfo
+        let peers = PeerAddress::resolve("localhost").unwrap();
+        assert!(!peers.is_empty());
+        assert!(peers.iter().all(|peer| peer.ip.is_loopback()));
+        for (i, peer) in peers.iter().enumerate() {
+            assert!(!peers[i + 1..].contains(peer));
+        }
+
+        assert!(matches!(
+            PeerAddress::resolve("no-such-host.invalid"),
+            Err(PeerAddressError::Unresolvable(host, _)) if host == "no-such-host.invalid"
+        ));
+    }
+
+    /// Runs a classic BPF program the way the kernel would, for the few
+    /// instructions [`echo_request_filter`] uses. Loads past the end of the
+    /// packet drop it, like in the kernel.
+    #[cfg(target_os = "linux")]
+    fn run_filter(program: &[libc::sock_filter], packet: &[u8]) -> u32 {
+        let (mut a, mut x, mut pc) = (0, 0, 0);
+        loop {
+            let instruction = program[pc];
+            pc += 1;
+            let (code, k) = (instruction.code as u32, instruction.k);
+            if code == libc::BPF_LDX | libc::BPF_IMM {
+                x = k;
+            } else if code == libc::BPF_LDX | libc::BPF_B | libc::BPF_MSH {
+                let Some(byte) = packet.get(k as usize) else {
+                    return 0;
+                };
+                x = (byte & 0x0f) as u32 * 4;
+            } else if code & 0x07 == libc::BPF_LD && code & 0xe0 == libc::BPF_IND {
+                let size = match code & 0x18 {
+                    libc::BPF_W => 4,
+                    libc::BPF_H => 2,
+                    libc::BPF_B => 1,
+                    size => panic!("unsupported load size {size:#x}"),
+                };
+                let offset = (x + k) as usize;
+                let Some(bytes) = packet.get(offset..offset + size) else {
+                    return 0;
+                };
+                a = bytes
+                    .iter()
+                    .fold(0, |value, byte| value << 8 | *byte as u32);
+            } else if code == libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K {
+                pc += if a == k {
+                    instruction.jt
+                } else {
+                    instruction.jf
+                } as usize;
+            } else if code == libc::BPF_RET | libc::BPF_K {
+                return k;
+            } else {
+                panic!("unsupported instruction {code:#x}");
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn only_icmpong_echo_requests_pass_the_filter() {
+        let current = crate::IcmPongPacket::Start.encode(crate::PROTOCOL_VERSION, 1);
+        // the magic used to start in the identifier
+        let legacy = crate::IcmPongPacket::Start.encode(crate::LEGACY_PROTOCOL_VERSION, 1);
+        // payloads that get partway through the checks, which would pass if
+        // a jump went one instruction too far
+        let mut near_misses = vec![current.clone(), current.clone(), current.clone()];
+        near_misses[0][6] = b'?';
+        near_misses[1][..4].copy_from_slice(&[0, 0, 0, b'g']);
+        near_misses[2][4..6].copy_from_slice(&[0, b'g']);
+        for ipv6 in [true, false] {
+            let (echo_request, echo_reply) = if ipv6 {
+                (Icmpv6Types::EchoRequest.0, Icmpv6Types::EchoReply.0)
+            } else {
+                (IcmpTypes::EchoRequest.0, IcmpTypes::EchoReply.0)
+            };
+            let program = echo_request_filter(ipv6);
+            let passes = |icmp_type: u8, packet: &[u8], ip_header_length: usize| {
+                let mut ip_header = vec![0; ip_header_length];
+                if let Some(first) = ip_header.first_mut() {
+                    *first = 0x40 | (ip_header_length / 4) as u8;
+                }
+                let received = [ip_header.as_slice(), &[icmp_type, 0, 0, 0], packet].concat();
+                run_filter(&program, &received) != 0
+            };
+            let ip_header_lengths: &[usize] = if ipv6 { &[0] } else { &[20, 24] };
+            for &ip_header_length in ip_header_lengths {
+                let current = [[0x12, 0x34, 0, 1].as_slice(), &current].concat();
+                assert!(passes(echo_request, &current, ip_header_length));
+                assert!(passes(echo_request, &legacy, ip_header_length));
+                for near_miss in &near_misses {
+                    let near_miss = [[0x12, 0x34, 0, 1].as_slice(), near_miss].concat();
+                    assert!(!passes(echo_request, &near_miss, ip_header_length));
+                }
+                assert!(!passes(echo_reply, &current, ip_header_length));
+                assert!(!passes(b'g', &current, ip_header_length));
+                assert!(!passes(echo_request, &current[..6], ip_header_length));
+            }
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use unicode_width::UnicodeWidthChar;
+
 pub const X_MINIMUM: u16 = 0;
 pub const Y_MINIMUM: u16 = 0;
 pub const X_MAXIMUM: u16 = 79;
@@ -17,12 +19,12 @@ pub struct GameObject {
 }
 
 impl GameObject {
-    pub fn get_ymin(self: &Self) -> u16 {
+    pub fn get_ymin(&self) -> u16 {
         self.y_position
     }
 
-    pub fn get_ymax(self: &Self) -> u16 {
-        self.y_position + self.size
+    pub fn get_ymax(&self) -> u16 {
+        self.y_position.saturating_add(self.size)
     }
 }
 
@@ -41,55 +43,96 @@ impl GameObject {
     }
 }
 
+/// Makes text from the peer safe to draw: control characters (which
+/// includes the escape starting terminal escape sequences) become `?`, as do
+/// characters taking up two columns, and zero-width ones are dropped, so that
+/// every character left takes up exactly one cell.
+pub fn sanitize(text: &str) -> String {
+    text.chars()
+        .filter_map(|c| match c.width() {
+            _ if c.is_control() => Some('?'),
+            Some(0) => None,
+            Some(1) => Some(c),
+            _ => Some('?'),
+        })
+        .collect()
+}
+
 pub struct Field {
-    pub field_data: [u8; FIELD_SIZE as usize],
+    pub field_data: [char; FIELD_SIZE as usize],
 }
 
 impl Field {
     pub fn new() -> Self {
         Self {
-            field_data: [b' '; FIELD_SIZE as usize],
+            field_data: [' '; FIELD_SIZE as usize],
         }
     }
 
-    pub fn clear(self: &mut Self) {
+    pub fn clear(&mut self) {
         for i in 0..self.field_data.len() {
             let x: u16 = i as u16 % X_MAXIMUM;
             let y: u16 = i as u16 / X_MAXIMUM;
-            let c: u8;
+            let c: char;
 
             if y == Y_MINIMUM || y == Y_MAXIMUM - 1 {
-                c = b'-';
+                c = '-';
             } else if x == (X_MAXIMUM - X_MINIMUM) / 2 {
-                c = b'\'';
+                c = '\'';
             } else if x == X_MINIMUM || x == X_MAXIMUM - 1 {
-                c = b'|';
+                c = '|';
             } else {
-                c = b' ';
+                c = ' ';
             }
 
-            self.field_data[i as usize] = c;
+            self.field_data[i] = c;
         }
     }
 
-    pub fn get_idx(self: &Self, x: &u16, y: &u16) -> usize {
-        (x + y * X_MAXIMUM) as usize
+    pub fn get_idx(&self, x: &u16, y: &u16) -> usize {
+        *x as usize + *y as usize * X_MAXIMUM as usize
     }
 
-    pub fn draw(self: &mut Self, game: &GameObject) {
+    pub fn draw(&mut self, game: &GameObject) {
         let x = game.x_position;
         for y in game.get_ymin()..game.get_ymax() {
             let index = self.get_idx(&x, &y);
             if self.field_data.len() > index {
-                self.field_data[index] = game.pixel;
+                self.field_data[index] = game.pixel as char;
             }
         }
     }
 
-    pub fn write(self: &mut Self, x: u16, y: u16, text: &str) {
+    /// Writes `text` (after [`sanitize`]) one character per cell, cutting it
+    /// off at the end of the row.
+    pub fn write(&mut self, x: u16, y: u16, text: &str) {
         let i = self.get_idx(&x, &y);
-        text.as_bytes().iter().enumerate().for_each(|(j, c)| {
-            self.field_data[i + j] = *c;
-        });
+        let row_end = self.get_idx(&X_MAXIMUM, &y).min(self.field_data.len());
+        for (cell, c) in (i..row_end).zip(sanitize(text).chars()) {
+            self.field_data[cell] = c;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peer_text_is_sanitized() {
+        assert_eq!(sanitize("player"), "player");
+        assert_eq!(sanitize("\x1b]0;pwned\x07"), "?]0;pwned?");
+        assert_eq!(sanitize("\u{9b}31m\r\n"), "?31m??");
+        assert_eq!(sanitize("Zoë"), "Zoë");
+        assert_eq!(sanitize("e\u{301}\u{200b}x"), "ex");
+        assert_eq!(sanitize("名前"), "??");
+
+        let mut field = Field::new();
+        field.clear();
+        field.write(X_MAXIMUM - 2, Y_MINIMUM, "ñame");
+        let row_end = field.get_idx(&X_MAXIMUM, &Y_MINIMUM);
+        assert_eq!(field.field_data[row_end - 2..row_end], ['ñ', 'a']);
+        assert_eq!(field.field_data[row_end], '|');
+        field.write(X_MINIMUM, Y_MAXIMUM - 1, "end of the field");
     }
 }